@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use metarflow::config::Config;
 use metarflow::models::AppState;
 use metarflow::routes::create_router;
 use tower_layer::Layer;
@@ -7,7 +8,7 @@ use vercel_runtime::axum::VercelLayer;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let state = Arc::new(AppState {});
+    let state = Arc::new(AppState::new(Config::from_env()));
     let app = create_router(state);
     let service = VercelLayer::new().layer(app.into_service());
 
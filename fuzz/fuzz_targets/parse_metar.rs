@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use metarflow::models::DecodeOptions;
+use metarflow::services::parse_metar;
+use metarflow::utils::decode_weather;
+
+// feeds arbitrary bytes to the parser, it should never panic however broken the report is
+fuzz_target!(|data: &[u8]| {
+    let metar = String::from_utf8_lossy(data);
+    let _ = parse_metar(&metar, "XXXX", &DecodeOptions::default());
+    for token in metar.split_whitespace() {
+        let _ = decode_weather(token);
+    }
+});
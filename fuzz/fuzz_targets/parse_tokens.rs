@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use metarflow::models::DecodeOptions;
+use metarflow::services::parse_metar;
+use metarflow::utils::decode_weather;
+
+// joins arbitrary tokens with spaces, so the fuzzer spends its time on group contents rather than whitespace
+fuzz_target!(|tokens: Vec<String>| {
+    let metar = tokens.join(" ");
+    let _ = parse_metar(&metar, "XXXX", &DecodeOptions::default());
+    for token in &tokens {
+        let _ = decode_weather(token);
+    }
+});
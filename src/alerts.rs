@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::models::{AppState, CategoryAlert, CategoryAlertJson};
+use crate::services::fetch_metar;
+use crate::utils::category_rank;
+
+// the least time between two alerts, so a category flapping around the threshold doesn't flood the webhook
+pub const ALERT_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+// where an alert stands between polls
+#[derive(Default)]
+pub struct AlertTracker {
+    // set once an alert for the current drop has gone out, cleared when the category recovers
+    pub below: bool,
+    pub last_alert: Option<Instant>,
+}
+
+// polls the alert airport and posts to the webhook when its flight category drops below the threshold
+pub async fn run_category_alert(state: Arc<AppState>, alert: CategoryAlert) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default();
+    let mut interval = tokio::time::interval(Duration::from_secs(alert.poll_seconds));
+    let mut tracker = AlertTracker::default();
+
+    loop {
+        interval.tick().await;
+        check_category_alert(&state, &alert, &client, &mut tracker).await;
+    }
+}
+
+// one poll of the alert airport. it alerts on the way down only, and not again until the category has
+// recovered and the cooldown has passed. a drop counts as alerted only once the webhook has taken it, so a
+// failed post or one held back by the cooldown is tried again on the next poll while the category stays down
+pub async fn check_category_alert(
+    state: &AppState,
+    alert: &CategoryAlert,
+    client: &reqwest::Client,
+    tracker: &mut AlertTracker,
+) {
+    let metar = match fetch_metar(&state.config.metar_source_url, &alert.icao).await {
+        Ok(metar) => metar,
+        Err(e) => {
+            eprintln!("Category alert fetch for {} failed: {}", alert.icao, e);
+            return;
+        }
+    };
+    let info = state.decoded.decode(&state.config.metar_source_url, &metar, &alert.icao, &state.config.decode);
+    let Some(category) = info.flight_category.clone() else {
+        return;
+    };
+
+    if category_rank(&category) >= category_rank(&alert.below) {
+        tracker.below = false;
+        return;
+    }
+    let cooled_down = tracker.last_alert.is_none_or(|at| at.elapsed() >= ALERT_COOLDOWN);
+    if tracker.below || !cooled_down {
+        return;
+    }
+
+    let body = CategoryAlertJson {
+        icao: &alert.icao,
+        category: &category,
+        threshold: &alert.below,
+        date_time: &info.date_time,
+        raw: &info.raw,
+    };
+    match client.post(&alert.webhook_url).json(&body).send().await {
+        Ok(response) if response.status().is_success() => {
+            tracker.below = true;
+            tracker.last_alert = Some(Instant::now());
+        }
+        Ok(response) => eprintln!("Category alert webhook returned {}", response.status()),
+        Err(e) => eprintln!("Category alert webhook failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use axum::{http::StatusCode, Router};
+    use crate::test_support;
+    use super::*;
+
+    const VFR_METAR: &str = "KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990";
+    const IFR_METAR: &str = "KJFK 161651Z 28010KT 2SM BR OVC006 12/10 A2990";
+
+    // an upstream serving whatever report is in the returned slot
+    async fn switchable_upstream(metar: &str) -> (String, Arc<Mutex<String>>) {
+        let current = Arc::new(Mutex::new(metar.to_string()));
+        let served = current.clone();
+        let router = Router::new().fallback(move || {
+            let body = served.lock().unwrap().clone();
+            async move { body }
+        });
+        (test_support::spawn_server(router).await, current)
+    }
+
+    fn mvfr_alert(webhook_url: &str) -> CategoryAlert {
+        CategoryAlert {
+            icao: "KJFK".to_string(),
+            below: "MVFR".to_string(),
+            webhook_url: webhook_url.to_string(),
+            poll_seconds: 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_category_drop_posts_one_alert() {
+        let (source, current) = switchable_upstream(VFR_METAR).await;
+        let webhook = test_support::upstream(StatusCode::OK, "application/json", "").await;
+        let state = AppState::new(test_support::config_with_source(&source));
+        let alert = mvfr_alert(&webhook.url);
+        let client = reqwest::Client::new();
+        let mut tracker = AlertTracker::default();
+
+        check_category_alert(&state, &alert, &client, &mut tracker).await;
+        assert_eq!(webhook.hits(), 0);
+
+        *current.lock().unwrap() = IFR_METAR.to_string();
+        for _ in 0..3 {
+            check_category_alert(&state, &alert, &client, &mut tracker).await;
+        }
+        assert_eq!(webhook.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_failed_post_is_retried() {
+        let (source, _) = switchable_upstream(IFR_METAR).await;
+        let failing = test_support::upstream(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", "").await;
+        let webhook = test_support::upstream(StatusCode::OK, "application/json", "").await;
+        let state = AppState::new(test_support::config_with_source(&source));
+        let client = reqwest::Client::new();
+        let mut tracker = AlertTracker::default();
+
+        check_category_alert(&state, &mvfr_alert(&failing.url), &client, &mut tracker).await;
+        assert_eq!(failing.hits(), 1);
+        assert!(!tracker.below);
+
+        check_category_alert(&state, &mvfr_alert(&webhook.url), &client, &mut tracker).await;
+        assert_eq!(webhook.hits(), 1);
+        assert!(tracker.below);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use crate::models::{DecodeOptions, MetarInfo};
+use crate::services::parse_metar;
+
+// the last decoded report for each station, so fetching an observation that hasn't changed skips parse_metar.
+// one entry per station, replaced as soon as the raw report or the upstream it came from is different
+#[derive(Default)]
+pub struct DecodedCache {
+    entries: Mutex<HashMap<String, CachedReport>>,
+    // how many reports actually went through parse_metar, the rest were served from the cache
+    misses: AtomicUsize,
+}
+
+// a station's decoded report and the upstream url it was fetched from
+pub struct CachedReport {
+    pub source_url: String,
+    pub raw_hash: u64,
+    pub info: MetarInfo,
+}
+
+impl DecodedCache {
+    // decodes the raw report, or hands back the earlier decode if the station's report from that upstream is unchanged
+    pub fn decode(&self, source_url: &str, raw: &str, icao: &str, options: &DecodeOptions) -> MetarInfo {
+        let key = raw_hash(raw);
+        if let Some(entry) = self.entries.lock().unwrap().get(icao) {
+            if entry.raw_hash == key && entry.source_url == source_url {
+                return entry.info.clone();
+            }
+        }
+
+        // parsed outside the lock so a slow decode doesn't hold up other stations
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let info = parse_metar(raw, icao, options);
+        let entry = CachedReport {
+            source_url: source_url.to_string(),
+            raw_hash: key,
+            info: info.clone(),
+        };
+        self.entries.lock().unwrap().insert(icao.to_string(), entry);
+        info
+    }
+
+    // for checking the cache is doing its job
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    // the last report decoded for the station from that upstream, however old, for serving while it's down
+    pub fn last(&self, source_url: &str, icao: &str) -> Option<MetarInfo> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(icao)
+            .filter(|entry| entry.source_url == source_url)
+            .map(|entry| entry.info.clone())
+    }
+}
+
+// hash of the raw report text, used to tell whether a station's observation has changed
+pub fn raw_hash(raw: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    raw.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    const SOURCE: &str = "https://aviationweather.gov/api/data/metar";
+
+    #[test]
+    fn unchanged_report_is_served_from_the_cache() {
+        let cache = DecodedCache::default();
+        let options = DecodeOptions::default();
+        let first = cache.decode(SOURCE, test_support::SAMPLE_METAR, "KJFK", &options);
+        let second = cache.decode(SOURCE, test_support::SAMPLE_METAR, "KJFK", &options);
+        assert_eq!(cache.misses(), 1);
+        assert_eq!(first.raw, second.raw);
+
+        // a new observation is decoded again
+        let newer = "KJFK 161651Z 29012KT 10SM FEW045 13/M02 A2991";
+        assert_eq!(cache.decode(SOURCE, newer, "KJFK", &options).raw, newer);
+        assert_eq!(cache.misses(), 2);
+        // and so is another station's
+        cache.decode(SOURCE, test_support::SAMPLE_METAR, "KLGA", &options);
+        assert_eq!(cache.misses(), 3);
+    }
+
+    #[tokio::test]
+    async fn repeat_requests_decode_once() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let state = std::sync::Arc::new(crate::models::AppState::new(test_support::config_with_source(&upstream.url)));
+        let app = crate::routes::create_router(state.clone());
+        for _ in 0..3 {
+            test_support::get(&app, "/api/metar?icao=KJFK").await;
+        }
+        assert_eq!(upstream.hits(), 3);
+        assert_eq!(state.decoded.misses(), 1);
+    }
+
+    #[test]
+    fn reports_are_kept_apart_by_upstream() {
+        let cache = DecodedCache::default();
+        let options = DecodeOptions::default();
+        cache.decode(SOURCE, test_support::SAMPLE_METAR, "KJFK", &options);
+        assert!(cache.last(SOURCE, "KJFK").is_some());
+        assert!(cache.last("http://staging.example.com/metar", "KJFK").is_none());
+
+        // the same text from another upstream is decoded again and replaces the entry
+        cache.decode("http://staging.example.com/metar", test_support::SAMPLE_METAR, "KJFK", &options);
+        assert_eq!(cache.misses(), 2);
+        assert!(cache.last(SOURCE, "KJFK").is_none());
+    }
+}
@@ -0,0 +1,271 @@
+use std::env;
+use axum::http::StatusCode;
+use crate::models::{CategoryAlert, DecodeOptions, DerivedFields, PopularAirport};
+use crate::utils::{category_rank, is_valid_station_code, MIN_CACHE_SECONDS};
+
+// where metars are fetched from unless METAR_SOURCE_URL says otherwise
+pub const DEFAULT_METAR_SOURCE_URL: &str = "https://aviationweather.gov/api/data/metar";
+
+// served at /robots.txt unless ROBOTS_FILE says otherwise, the home page is fine to index but result pages aren't
+pub const DEFAULT_ROBOTS_TXT: &str = "User-agent: *\nAllow: /$\nDisallow: /metar\nDisallow: /dashboard\nDisallow: /diff\nDisallow: /api/\n";
+
+// how often the category alert polls its airport unless ALERT_POLL_SECONDS says otherwise
+pub const DEFAULT_ALERT_POLL_SECONDS: u64 = 300;
+
+// settings read from environment variables at startup
+#[derive(Clone)]
+pub struct Config {
+    pub popular_airports: Vec<PopularAirport>,
+    pub metar_source_url: String,
+    pub check_upstream: bool,
+    pub hide_footer: bool,
+    pub hide_credits: bool,
+    pub api_token: Option<String>,
+    pub default_icao: Option<String>,
+    pub decode: DecodeOptions,
+    pub privacy_html: Option<String>,
+    pub robots_txt: String,
+    pub listen_uds: Option<String>,
+    pub stale_on_error: bool,
+    pub min_decode_completeness: Option<f32>,
+    pub force_https: bool,
+    // the host[:port] the site is served as, used for the https redirect and the only Host accepted when set
+    pub public_host: Option<String>,
+    pub security_headers: bool,
+    pub category_alert: Option<CategoryAlert>,
+    // origins allowed to call /api/* from the browser, empty for same-origin only, "*" for any
+    pub cors_origins: Vec<String>,
+    // hosts a request may switch the upstream to with source=, empty turns the override off
+    pub source_override_hosts: Vec<String>,
+    // the status for a station with no current report, 404 unless NO_REPORT_STATUS is 200
+    pub no_report_status: StatusCode,
+    // fetch the last few hours too and show which way temperature, pressure and wind are going
+    pub show_trends: bool,
+}
+
+impl Config {
+    // builds the config from the environment, using defaults for anything unset
+    pub fn from_env() -> Self {
+        let popular_airports = env::var("POPULAR_AIRPORTS")
+            .ok()
+            .map(|value| parse_popular_airports(&value))
+            .filter(|airports| !airports.is_empty())
+            .unwrap_or_else(default_popular_airports);
+
+        let metar_source_url = env::var("METAR_SOURCE_URL")
+            .ok()
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .filter(|url| !url.is_empty())
+            .unwrap_or_else(|| DEFAULT_METAR_SOURCE_URL.to_string());
+
+        Config {
+            popular_airports,
+            metar_source_url,
+            check_upstream: env_flag("CHECK_UPSTREAM"),
+            hide_footer: env_flag("HIDE_FOOTER"),
+            hide_credits: env_flag("HIDE_CREDITS"),
+            api_token: env::var("API_TOKEN")
+                .ok()
+                .map(|token| token.trim().to_string())
+                .filter(|token| !token.is_empty()),
+            default_icao: env::var("DEFAULT_ICAO")
+                .ok()
+                .map(|icao| icao.trim().to_uppercase())
+                .filter(|icao| is_valid_station_code(icao)),
+            decode: DecodeOptions {
+                precision: env::var("OUTPUT_PRECISION")
+                    .ok()
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .map(|places| places.min(6)),
+                derived: env::var("DERIVED_FIELDS")
+                    .ok()
+                    .map(|value| DerivedFields::from_list(&value))
+                    .unwrap_or_else(DerivedFields::all),
+                cloud_heights_meters: env_flag("CLOUD_HEIGHTS_METERS"),
+            },
+            privacy_html: read_file_from_env("PRIVACY_FILE"),
+            robots_txt: read_file_from_env("ROBOTS_FILE").unwrap_or_else(|| DEFAULT_ROBOTS_TXT.to_string()),
+            listen_uds: env::var("LISTEN_UDS")
+                .ok()
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty()),
+            stale_on_error: env_flag("STALE_ON_ERROR"),
+            min_decode_completeness: env::var("MIN_DECODE_COMPLETENESS")
+                .ok()
+                .and_then(|value| value.trim().parse::<f32>().ok())
+                .map(|min| min.clamp(0.0, 1.0)),
+            force_https: env_flag("FORCE_HTTPS"),
+            public_host: env::var("PUBLIC_HOST")
+                .ok()
+                .map(|host| host.trim().to_lowercase())
+                .filter(|host| {
+                    let valid = is_valid_host(host);
+                    if !valid && !host.is_empty() {
+                        eprintln!("Ignoring PUBLIC_HOST {:?}, it should be a host name with an optional port", host);
+                    }
+                    valid
+                }),
+            security_headers: env_flag("SECURITY_HEADERS"),
+            category_alert: category_alert_from_env(),
+            cors_origins: env::var("CORS_ORIGINS")
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                        .filter(|origin| !origin.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            source_override_hosts: env::var("SOURCE_OVERRIDE_HOSTS")
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(|host| host.trim().to_lowercase())
+                        .filter(|host| !host.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            no_report_status: match env::var("NO_REPORT_STATUS").ok().as_deref().map(str::trim) {
+                Some("200") => StatusCode::OK,
+                _ => StatusCode::NOT_FOUND,
+            },
+            show_trends: env_flag("SHOW_TRENDS"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            popular_airports: default_popular_airports(),
+            metar_source_url: DEFAULT_METAR_SOURCE_URL.to_string(),
+            check_upstream: false,
+            hide_footer: false,
+            hide_credits: false,
+            api_token: None,
+            default_icao: None,
+            decode: DecodeOptions::default(),
+            privacy_html: None,
+            robots_txt: DEFAULT_ROBOTS_TXT.to_string(),
+            listen_uds: None,
+            stale_on_error: false,
+            min_decode_completeness: None,
+            force_https: false,
+            public_host: None,
+            security_headers: false,
+            category_alert: None,
+            cors_origins: Vec::new(),
+            source_override_hosts: Vec::new(),
+            no_report_status: StatusCode::NOT_FOUND,
+            show_trends: false,
+        }
+    }
+}
+
+// the airports shown in the dropdown when POPULAR_AIRPORTS isn't set
+pub fn default_popular_airports() -> Vec<PopularAirport> {
+    [
+        ("KJFK", "JFK"),
+        ("EGLL", "Heathrow"),
+        ("KLAX", "LAX"),
+        ("KORD", "O'Hare"),
+        ("EDDF", "Frankfurt"),
+    ]
+    .iter()
+    .map(|(icao, name)| PopularAirport {
+        icao: icao.to_string(),
+        name: name.to_string(),
+    })
+    .collect()
+}
+
+// parses a list like "KJFK:JFK,EGLL:Heathrow,YSSY" into airports, the name after ':' is optional
+pub fn parse_popular_airports(value: &str) -> Vec<PopularAirport> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let mut pieces = entry.splitn(2, ':');
+            let icao = pieces.next()?.trim().to_uppercase();
+            if !is_valid_station_code(&icao) {
+                return None;
+            }
+            let name = pieces.next().map(|n| n.trim().to_string()).unwrap_or_default();
+            Some(PopularAirport { icao, name })
+        })
+        .collect()
+}
+
+// reads the file named by an environment variable, warning and returning None if it can't be read
+pub fn read_file_from_env(name: &str) -> Option<String> {
+    let path = env::var(name).ok().filter(|path| !path.trim().is_empty())?;
+    match std::fs::read_to_string(path.trim()) {
+        Ok(contents) => Some(contents),
+        Err(e) => {
+            eprintln!("Couldn't read {} ({}): {}, using the built-in default", name, path.trim(), e);
+            None
+        }
+    }
+}
+
+// the category alert, only when ALERT_ICAO, ALERT_BELOW and ALERT_WEBHOOK_URL are all set and valid
+pub fn category_alert_from_env() -> Option<CategoryAlert> {
+    let icao = env::var("ALERT_ICAO").ok()?.trim().to_uppercase();
+    let below = env::var("ALERT_BELOW").ok()?.trim().to_uppercase();
+    let webhook_url = env::var("ALERT_WEBHOOK_URL").ok()?.trim().to_string();
+    if !is_valid_station_code(&icao) || category_rank(&below).is_none() || webhook_url.is_empty() {
+        eprintln!("Ignoring the category alert, ALERT_ICAO or ALERT_BELOW isn't valid");
+        return None;
+    }
+    let poll_seconds = env::var("ALERT_POLL_SECONDS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_ALERT_POLL_SECONDS)
+        .max(MIN_CACHE_SECONDS);
+    Some(CategoryAlert {
+        icao,
+        below,
+        webhook_url,
+        poll_seconds,
+    })
+}
+
+// reads an on/off environment variable, accepting 1/true/yes/on
+pub fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+// a host name or address with an optional port, like "metar.example.com" or "localhost:3000", nothing else
+pub fn is_valid_host(host: &str) -> bool {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) => (name, Some(port)),
+        None => (host, None),
+    };
+    !name.is_empty()
+        && name.len() <= 253
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        && port.is_none_or(|port| !port.is_empty() && port.len() <= 5 && port.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn popular_airports_from_a_list() {
+        let airports = parse_popular_airports("EYVI:Vilnius, egll ,KJ&K,KJFK:JFK,lax:LAX,KJFKX");
+        let parsed: Vec<(&str, &str)> = airports.iter().map(|a| (a.icao.as_str(), a.name.as_str())).collect();
+        assert_eq!(parsed, [("EYVI", "Vilnius"), ("EGLL", ""), ("KJFK", "JFK"), ("LAX", "LAX")]);
+    }
+
+    #[test]
+    fn public_host_shapes() {
+        assert!(is_valid_host("metar.example.com"));
+        assert!(is_valid_host("localhost:3000"));
+        assert!(!is_valid_host("evil.com/path"));
+        assert!(!is_valid_host("host:"));
+        assert!(!is_valid_host(":443"));
+        assert!(!is_valid_host("user@host"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema};
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse},
+    Extension, Json,
+};
+use crate::models::{AppState, MetarInfo};
+use crate::services::fetch_metar;
+use crate::utils::is_valid_station_code;
+
+// the most airports a single metars query may ask for, each one is an upstream fetch
+pub const MAX_GRAPHQL_AIRPORTS: usize = 20;
+
+pub type MetarSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // the decoded metar for one airport
+    async fn metar(&self, ctx: &Context<'_>, icao: String) -> async_graphql::Result<MetarInfo> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        fetch_decoded(state.clone(), icao).await
+    }
+
+    // the decoded metars for several airports, fetched concurrently and returned in the order asked for
+    async fn metars(&self, ctx: &Context<'_>, icaos: Vec<String>) -> async_graphql::Result<Vec<MetarInfo>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        if icaos.len() > MAX_GRAPHQL_AIRPORTS {
+            return Err(format!("At most {} airports can be requested at once", MAX_GRAPHQL_AIRPORTS).into());
+        }
+
+        let tasks: Vec<_> = icaos
+            .into_iter()
+            .map(|icao| tokio::spawn(fetch_decoded(state.clone(), icao)))
+            .collect();
+        let mut results = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            results.push(task.await??);
+        }
+        Ok(results)
+    }
+}
+
+// the same fetch and decode path as the /metar and /api/metar handlers
+async fn fetch_decoded(state: Arc<AppState>, icao: String) -> async_graphql::Result<MetarInfo> {
+    let icao = icao.trim().to_uppercase();
+    if !is_valid_station_code(&icao) {
+        return Err(format!("Invalid airport code {:?}", icao).into());
+    }
+    let metar = fetch_metar(&state.config.metar_source_url, &icao)
+        .await
+        .map_err(|e| format!("Error fetching METAR for {}: {}", icao, e))?;
+    Ok(state.decoded.decode(&state.config.metar_source_url, &metar, &icao, &state.config.decode))
+}
+
+// builds the schema once at startup, the app state is attached to each request instead
+pub fn build_schema() -> MetarSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(4)
+        .finish()
+}
+
+// runs a graphql query posted as json
+pub async fn graphql_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(schema): Extension<MetarSchema>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request.data(state)).await)
+}
+
+// serves the graphiql playground for trying out queries in the browser
+pub async fn graphiql() -> impl IntoResponse {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{header, Request, StatusCode}};
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn query_returns_only_the_selected_fields() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let query = r#"{"query":"{ metar(icao: \"kjfk\") { station windSpeedKnots flightCategory } }"}"#;
+        let request = Request::post("/graphql")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(query))
+            .unwrap();
+        let (status, _, body) = test_support::send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"data":{"metar":{"station":"KJFK","windSpeedKnots":10,"flightCategory":"VFR"}}}"#);
+    }
+}
@@ -1,22 +1,176 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use axum::{
-    extract::Query,
+    extract::{Query, State},
     http::{header, HeaderMap, HeaderValue, StatusCode},
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Redirect, Response},
+    Json,
 };
-use crate::models::{MetarInfo, MetarQuery};
-use crate::services::{fetch_metar, parse_metar};
-use crate::utils::format_stat_value;
+use crate::config::Config;
+use crate::models::{AppState, CategoryJson, ConvertJson, ConvertQuery, DashboardQuery, DiffJson, DiffQuery, ErrorJson, FieldChange, HistoryJson, HistoryQuery, MetarInfo, MetarJson, MetarQuery, NoReportJson, PageOptions, PageQuery, PopularAirport, Trend, UndecodableJson, ValueTrend, VersionInfo};
+use crate::services::{diff_reports, fetch_metar, fetch_metar_history, fetch_override_metar, fetch_trend, parse_metar, NoReport, PARSER_VERSION};
+use crate::utils::{cache_max_age, celsius_to_fahrenheit, conversion_unit_names, convert_unit, escape_html, format_decimal, format_stat_value, is_valid_station_code,
+    shell_quote, MIN_CACHE_SECONDS};
 
-// serves the home page with the search form
-pub async fn index() -> Html<String> {
-    let template = include_str!("../templates/index.html");
-    Html(template.to_string())
+// response header naming the decoder version behind a result
+pub const PARSER_VERSION_HEADER: &str = "x-metar-parser-version";
+
+// shown when the requested code isn't a plain 4-character ICAO or 3-character IATA code
+const INVALID_CODE_MESSAGE: &str = "Airport codes should be 4 letters or digits for ICAO (e.g., KJFK, EGLL, YSSY) or 3 for IATA (e.g., JFK)";
+
+// the most airports one dashboard fetches, each is a separate upstream request
+pub const MAX_DASHBOARD_AIRPORTS: usize = 20;
+
+// how far back /api/metar/history looks by default and at most, and how many reports go on a page
+pub const DEFAULT_HISTORY_HOURS: u32 = 6;
+pub const MAX_HISTORY_HOURS: u32 = 24;
+pub const DEFAULT_HISTORY_PER_PAGE: usize = 6;
+pub const MAX_HISTORY_PER_PAGE: usize = 24;
+
+// how far back the diff looks for the two most recent reports, routine ones come hourly
+pub const DIFF_HOURS: u32 = 3;
+
+const FOOTER_DISCLAIMER: &str = r#"        <p class="disclaimer">The information on this website is intended for educational purposes only. Not for operational use.</p>"#;
+const FOOTER_CREDITS: &str = r#"        <p class="credits">Created by <a href="https://github.com/Balionelis/metarflow" target="_blank" rel="noopener noreferrer"><svg class="github-icon" viewBox="0 0 16 16" fill="currentColor"><path d="M8 0C3.58 0 0 3.58 0 8c0 3.54 2.29 6.53 5.47 7.59.4.07.55-.17.55-.38 0-.19-.01-.82-.01-1.49-2.01.37-2.53-.49-2.69-.94-.09-.23-.48-.94-.82-1.13-.28-.15-.68-.52-.01-.53.63-.01 1.08.58 1.23.82.72 1.21 1.87.87 2.33.66.07-.52.28-.87.51-1.07-1.78-.2-3.64-.89-3.64-3.95 0-.87.31-1.59.82-2.15-.08-.2-.36-1.02.08-2.12 0 0 .67-.21 2.2.82.64-.18 1.32-.27 2-.27.68 0 1.36.09 2 .27 1.53-1.04 2.2-.82 2.2-.82.44 1.1.16 1.92.08 2.12.51.56.82 1.27.82 2.15 0 3.07-1.87 3.75-3.65 3.95.29.25.54.73.54 1.48 0 1.07-.01 1.93-.01 2.2 0 .21.15.46.55.38A8.012 8.012 0 0 0 16 8c0-4.42-3.58-8-8-8z"/></svg>Balionelis</a>. Licensed under <a href="https://github.com/Balionelis/metarflow/blob/main/LICENSE" target="_blank" rel="noopener noreferrer">GPL-3.0</a>. <a href="/privacy">Privacy</a>.</p>"#;
+
+// serves the home page with the search form, or sends single-airport deployments straight to DEFAULT_ICAO
+pub async fn index(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PageQuery>,
+) -> Response {
+    let options = PageOptions::new(&state.config, params.embed.as_deref());
+
+    if let Some(icao) = &state.config.default_icao {
+        let mut location = format!("/metar?icao={}", icao);
+        if params.embed.is_some() && options.embed {
+            location.push_str("&embed=1");
+        }
+        return Redirect::temporary(&location).into_response();
+    }
+
+    let template = include_str!("../templates/index.html")
+        .replace("{{POPULAR_AIRPORTS}}", &render_popular_airports(&state.config.popular_airports))
+        .replace("{{EMBED_INPUT}}", &render_embed_input(&options))
+        .replace("{{FOOTER}}", &render_footer(&options));
+    Html(template).into_response()
+}
+
+// the error page with the message escaped, served with the given status
+pub fn render_error(status: StatusCode, message: &str) -> Response {
+    let html = include_str!("../templates/error.html").replace("{{ERROR}}", &escape_html(message));
+    (status, Html(html)).into_response()
+}
+
+// the page for an airport the upstream has no current report for, answered with NO_REPORT_STATUS
+pub fn render_no_report(config: &Config, icao: &str, options: &PageOptions) -> Response {
+    let html = include_str!("../templates/no_report.html")
+        .replace("{{ICAO}}", &escape_html(icao))
+        .replace("{{FOOTER}}", &render_footer(options));
+    (config.no_report_status, Html(html)).into_response()
+}
+
+// serves the multi-airport dashboard, one card per airport in icaos or the popular airports when none are given
+pub async fn dashboard(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DashboardQuery>,
+) -> Response {
+    let options = PageOptions::new(&state.config, params.embed.as_deref());
+
+    let icaos: Vec<String> = match params.icaos.as_deref().map(str::trim).filter(|list| !list.is_empty()) {
+        Some(list) => list
+            .split(',')
+            .map(|icao| icao.trim().to_uppercase())
+            .filter(|icao| !icao.is_empty())
+            .collect(),
+        None => state.config.popular_airports.iter().map(|airport| airport.icao.clone()).collect(),
+    };
+    if icaos.iter().any(|icao| !is_valid_station_code(icao)) {
+        return render_error(StatusCode::BAD_REQUEST, INVALID_CODE_MESSAGE);
+    }
+    if icaos.len() > MAX_DASHBOARD_AIRPORTS {
+        let message = format!("The dashboard shows at most {} airports", MAX_DASHBOARD_AIRPORTS);
+        return render_error(StatusCode::BAD_REQUEST, &message);
+    }
+
+    // fetched concurrently, the cards keep the order they were asked for in
+    let tasks: Vec<_> = icaos
+        .iter()
+        .map(|icao| {
+            let state = state.clone();
+            let icao = icao.clone();
+            tokio::spawn(async move {
+                let metar = fetch_metar(&state.config.metar_source_url, &icao)
+                    .await
+                    .map_err(|e| if e.is::<NoReport>() { e.to_string() } else { format!("Error fetching METAR: {}", e) })?;
+                Ok::<_, String>(state.decoded.decode(&state.config.metar_source_url, &metar, &icao, &state.config.decode))
+            })
+        })
+        .collect();
+    let mut cards = Vec::with_capacity(tasks.len());
+    for (icao, task) in icaos.iter().zip(tasks) {
+        let result = task.await.unwrap_or_else(|e| Err(e.to_string()));
+        cards.push(render_dashboard_card(icao, &result));
+    }
+
+    let template = include_str!("../templates/dashboard.html")
+        .replace("{{CARDS}}", &cards.join("\n"))
+        .replace("{{FOOTER}}", &render_footer(&options));
+    Html(template).into_response()
+}
+
+// one dashboard card: the station, its color-coded flight category, wind and visibility
+pub fn render_dashboard_card(icao: &str, result: &Result<MetarInfo, String>) -> String {
+    let info = match result {
+        Ok(info) => info,
+        Err(e) => {
+            return format!(
+                r#"        <div class="card" data-icao="{icao}">
+            <div class="card-header"><a href="/metar?icao={icao}">{icao}</a><span class="category">N/A</span></div>
+            <div class="card-error">{error}</div>
+        </div>"#,
+                icao = icao,
+                error = escape_html(e),
+            );
+        }
+    };
+    let category_class = match &info.flight_category {
+        Some(category) => format!(" category-{}", category.to_lowercase()),
+        None => String::new(),
+    };
+    let line = |label: &str, field: &str, value: &str| {
+        let (class, value) = format_stat_value(value, "N/A");
+        format!(r#"            <div class="card-line{}" data-field="{}">{}: {}</div>"#, class, field, label, value)
+    };
+    format!(
+        r#"        <div class="card" data-icao="{icao}">
+            <div class="card-header"><a href="/metar?icao={icao}">{icao}</a><span class="category{category_class}">{category}</span></div>
+{wind}
+{visibility}
+{limiting}
+        </div>"#,
+        icao = icao,
+        category_class = category_class,
+        category = info.flight_category.as_deref().unwrap_or("N/A"),
+        wind = line("Wind", "wind", &info.wind),
+        visibility = line("Visibility", "visibility", &info.visibility),
+        limiting = line("Limited by", "limiting_factor", info.limiting_factor.as_deref().unwrap_or_default()),
+    )
+}
+
+// serves the privacy policy page, or the deployment's own one from PRIVACY_FILE
+pub async fn privacy(State(state): State<Arc<AppState>>) -> Html<String> {
+    match &state.config.privacy_html {
+        Some(html) => Html(html.clone()),
+        None => Html(include_str!("../templates/privacy.html").to_string()),
+    }
 }
 
-// serves the privacy policy page
-pub async fn privacy() -> Html<String> {
-    let template = include_str!("../templates/privacy.html");
-    Html(template.to_string())
+// serves the crawler policy, the default or the one from ROBOTS_FILE
+pub async fn robots(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        state.config.robots_txt.clone(),
+    )
 }
 
 // serves the favicon svg file
@@ -30,34 +184,724 @@ pub async fn favicon() -> impl IntoResponse {
     (StatusCode::OK, headers, svg)
 }
 
+// reports the crate version and the commit/time it was built from, filled in by build.rs
+pub async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        build_time: env!("BUILD_TIME"),
+    })
+}
+
 // handles requests to fetch and display metar data for an airport
 pub async fn fetch_metar_handler(
+    State(state): State<Arc<AppState>>,
     Query(params): Query<MetarQuery>,
 ) -> impl IntoResponse {
     let icao = params.icao.trim().to_uppercase();
 
-    if icao.len() != 4 {
-        let html = include_str!("../templates/error.html")
-            .replace("{{ERROR}}", "ICAO codes should be 4 characters (e.g., KJFK, EGLL, YSSY)");
-        return (StatusCode::BAD_REQUEST, Html(html)).into_response();
+    if !is_valid_station_code(&icao) {
+        return render_error(StatusCode::BAD_REQUEST, INVALID_CODE_MESSAGE);
     }
+    let source_url = match request_source_url(&state.config, params.source.as_deref()) {
+        Ok(url) => url,
+        Err(message) => return render_error(StatusCode::BAD_REQUEST, &message),
+    };
 
-    match fetch_metar(&icao).await {
-        Ok(metar) => {
-            let info = parse_metar(&metar, &icao);
-            let html = format_results_page(&info);
-            Html(html).into_response()
+    let (info, stale) = match fetch_request_info(&state, source_url.as_deref(), &icao).await {
+        Ok(fetched) => fetched,
+        Err(e) if e.is::<NoReport>() => {
+            let options = PageOptions::new(&state.config, params.embed.as_deref());
+            return render_no_report(&state.config, &icao, &options);
         }
         Err(e) => {
-            let html = include_str!("../templates/error.html")
-                .replace("{{ERROR}}", &format!("Error fetching METAR: {}", e));
-            (StatusCode::INTERNAL_SERVER_ERROR, Html(html)).into_response()
+            let message = format!("Error fetching METAR: {}", e);
+            return render_error(StatusCode::INTERNAL_SERVER_ERROR, &message);
+        }
+    };
+
+    let mut options = PageOptions::new(&state.config, params.embed.as_deref())
+        .with_print(params.print.as_deref());
+    options.stale = stale;
+    options.undecodable = is_undecodable(&info, &state.config);
+    if state.config.show_trends && !stale && source_url.is_none() {
+        options.trend = fetch_trend(&state.config.metar_source_url, &icao, &state.config.decode).await;
+    }
+    let html = format_results_page(&info, &state.config.popular_airports, &options);
+    let headers = [
+        (PARSER_VERSION_HEADER, PARSER_VERSION.to_string()),
+        (header::CACHE_CONTROL.as_str(), response_cache_control(&info, stale)),
+    ];
+    (headers, Html(html)).into_response()
+}
+
+// handles requests for the decoded metar as json
+pub async fn fetch_metar_json_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MetarQuery>,
+) -> impl IntoResponse {
+    let icao = params.icao.trim().to_uppercase();
+
+    if !is_valid_station_code(&icao) {
+        let error = ErrorJson {
+            error: INVALID_CODE_MESSAGE.to_string(),
+        };
+        return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+    }
+    let source_url = match request_source_url(&state.config, params.source.as_deref()) {
+        Ok(url) => url,
+        Err(message) => return (StatusCode::BAD_REQUEST, Json(ErrorJson { error: message })).into_response(),
+    };
+
+    let (info, stale) = match fetch_request_info(&state, source_url.as_deref(), &icao).await {
+        Ok(fetched) => fetched,
+        Err(e) if e.is::<NoReport>() => return no_report_json(&state.config, &icao),
+        Err(e) => {
+            let error = ErrorJson {
+                error: format!("Error fetching METAR: {}", e),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error)).into_response();
+        }
+    };
+
+    if is_undecodable(&info, &state.config) {
+        let error = UndecodableJson {
+            error: format!("Couldn't decode the report for {}", icao),
+            raw: &info.raw,
+            warnings: &info.warnings,
+            decode_completeness: info.decode_completeness,
+            missing_fields: &info.missing_fields,
+        };
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response();
+    }
+
+    let trend = if state.config.show_trends && !stale && source_url.is_none() {
+        fetch_trend(&state.config.metar_source_url, &icao, &state.config.decode).await
+    } else {
+        None
+    };
+    let body = MetarJson {
+        parser_version: PARSER_VERSION,
+        stale,
+        field_errors: matches!(params.field_errors.as_deref(), Some("1") | Some("true")).then_some(info.field_errors.as_slice()),
+        trend: trend.as_ref(),
+        metar: &info,
+    };
+    let headers = [
+        (PARSER_VERSION_HEADER, PARSER_VERSION.to_string()),
+        (header::CACHE_CONTROL.as_str(), response_cache_control(&info, stale)),
+    ];
+    (headers, Json(body)).into_response()
+}
+
+// handles requests for what changed between the two most recent reports, as json
+pub async fn diff_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DiffQuery>,
+) -> Response {
+    let icao = params.icao.trim().to_uppercase();
+    let error = |status: StatusCode, message: String| (status, Json(ErrorJson { error: message })).into_response();
+
+    if !is_valid_station_code(&icao) {
+        return error(StatusCode::BAD_REQUEST, INVALID_CODE_MESSAGE.to_string());
+    }
+    let (previous, latest) = match fetch_report_pair(&state, &icao).await {
+        Ok(Some(pair)) => pair,
+        Ok(None) => return error(StatusCode::NOT_FOUND, single_report_message(&icao)),
+        Err(e) if e.is::<NoReport>() => return no_report_json(&state.config, &icao),
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Error fetching METAR history: {}", e)),
+    };
+
+    let changes = diff_reports(&previous, &latest);
+    let body = DiffJson {
+        parser_version: PARSER_VERSION,
+        icao: &icao,
+        previous_raw: &previous.raw,
+        latest_raw: &latest.raw,
+        deteriorated: is_deteriorated(&changes),
+        changes: &changes,
+    };
+    ([(PARSER_VERSION_HEADER, PARSER_VERSION)], Json(body)).into_response()
+}
+
+// serves the diff as a page, with the changed fields highlighted
+pub async fn diff_page(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DiffQuery>,
+) -> Response {
+    let icao = params.icao.trim().to_uppercase();
+    if !is_valid_station_code(&icao) {
+        return render_error(StatusCode::BAD_REQUEST, INVALID_CODE_MESSAGE);
+    }
+    let (previous, latest) = match fetch_report_pair(&state, &icao).await {
+        Ok(Some(pair)) => pair,
+        Ok(None) => return render_error(StatusCode::NOT_FOUND, &single_report_message(&icao)),
+        Err(e) if e.is::<NoReport>() => {
+            let options = PageOptions::new(&state.config, params.embed.as_deref());
+            return render_no_report(&state.config, &icao, &options);
+        }
+        Err(e) => return render_error(StatusCode::INTERNAL_SERVER_ERROR, &format!("Error fetching METAR history: {}", e)),
+    };
+
+    let options = PageOptions::new(&state.config, params.embed.as_deref());
+    let html = include_str!("../templates/diff.html")
+        .replace("{{ICAO}}", &icao)
+        .replace("{{PREVIOUS_RAW}}", &escape_html(&previous.raw))
+        .replace("{{LATEST_RAW}}", &escape_html(&latest.raw))
+        .replace("{{ROWS}}", &render_diff_rows(&diff_reports(&previous, &latest)))
+        .replace("{{FOOTER}}", &render_footer(&options));
+    Html(html).into_response()
+}
+
+// builds a table row per compared field, the changed ones marked so the page can highlight them
+pub fn render_diff_rows(changes: &[FieldChange]) -> String {
+    changes
+        .iter()
+        .map(|change| {
+            let (label, unit) = match change.field {
+                "wind" => ("Wind", " kt"),
+                "visibility" => ("Visibility", " SM"),
+                "clouds" => ("Ceiling", " ft"),
+                _ => ("Flight category", ""),
+            };
+            let delta = match change.delta {
+                Some(delta) if delta != 0.0 && change.field == "flight_category" => {
+                    if delta > 0.0 { "better".to_string() } else { "worse".to_string() }
+                }
+                Some(delta) if delta != 0.0 => format!("{:+}{}", delta, unit),
+                _ => String::new(),
+            };
+            format!(
+                r#"        <tr class="{}">
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td class="delta">{}</td>
+        </tr>"#,
+                if change.changed { "changed" } else { "unchanged" },
+                label,
+                escape_html(&change.before),
+                escape_html(&change.after),
+                delta
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// handles requests for the decoded reports from the last few hours, a page at a time
+pub async fn history_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HistoryQuery>,
+) -> Response {
+    let icao = params.icao.trim().to_uppercase();
+    let bad_request = |message: String| (StatusCode::BAD_REQUEST, Json(ErrorJson { error: message })).into_response();
+
+    if !is_valid_station_code(&icao) {
+        return bad_request(INVALID_CODE_MESSAGE.to_string());
+    }
+    let page = params.page.unwrap_or(1);
+    let per_page = params.per_page.unwrap_or(DEFAULT_HISTORY_PER_PAGE);
+    if page == 0 {
+        return bad_request("Pages are numbered from 1".to_string());
+    }
+    if per_page == 0 || per_page > MAX_HISTORY_PER_PAGE {
+        return bad_request(format!("per_page should be between 1 and {}", MAX_HISTORY_PER_PAGE));
+    }
+    if params.hours == Some(0) {
+        return bad_request("hours should be at least 1".to_string());
+    }
+    // asking for more than the cap gets the cap rather than an error
+    let hours = params.hours.unwrap_or(DEFAULT_HISTORY_HOURS).min(MAX_HISTORY_HOURS);
+
+    let reports = match fetch_metar_history(&state.config.metar_source_url, &icao, hours).await {
+        Ok(reports) => reports,
+        Err(e) if e.is::<NoReport>() => return no_report_json(&state.config, &icao),
+        Err(e) => {
+            let error = ErrorJson {
+                error: format!("Error fetching METAR history: {}", e),
+            };
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(error)).into_response();
+        }
+    };
+
+    // only the requested page gets decoded, a page past the end is just empty
+    let observations = reports
+        .iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .map(|raw| parse_metar(raw, &icao, &state.config.decode))
+        .collect();
+    let body = HistoryJson {
+        parser_version: PARSER_VERSION,
+        icao: &icao,
+        hours,
+        page,
+        per_page,
+        total: reports.len(),
+        total_pages: reports.len().div_ceil(per_page),
+        observations,
+    };
+    ([(PARSER_VERSION_HEADER, PARSER_VERSION)], Json(body)).into_response()
+}
+
+// handles requests for just the flight category, for indicator lamps and other small clients
+pub async fn category_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MetarQuery>,
+) -> impl IntoResponse {
+    let (icao, info, stale) = match fetch_api_info(&state, &params.icao).await {
+        Ok(fetched) => fetched,
+        Err(response) => return response,
+    };
+
+    // no visibility or sky condition in the report, or the category switched off with DERIVED_FIELDS
+    let Some(category) = info.flight_category.as_deref() else {
+        let error = ErrorJson {
+            error: format!("No flight category could be worked out for {}", icao),
+        };
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response();
+    };
+
+    let body = CategoryJson {
+        icao: &icao,
+        category,
+        vfr: category == "VFR",
+        mvfr: category == "MVFR",
+        ifr: category == "IFR",
+        lifr: category == "LIFR",
+        stale,
+    };
+    let headers = [
+        (PARSER_VERSION_HEADER, PARSER_VERSION.to_string()),
+        (header::CACHE_CONTROL.as_str(), response_cache_control(&info, stale)),
+    ];
+    (headers, Json(body)).into_response()
+}
+
+// converts a value between units, like /api/convert?value=29.92&from=inhg&to=hpa
+pub async fn convert_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ConvertQuery>,
+) -> Response {
+    let Some(value) = params.value.trim().parse::<f64>().ok().filter(|value| value.is_finite()) else {
+        let error = ErrorJson {
+            error: format!("Can't convert {:?}, the value has to be a number", params.value),
+        };
+        return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+    };
+
+    // "km/h" is the usual way to write it, but the unit names have no punctuation
+    let unit = |name: &str| name.trim().to_lowercase().replace('/', "");
+    let (from, to) = (unit(&params.from), unit(&params.to));
+
+    let Some(result) = convert_unit(value, &from, &to) else {
+        let error = ErrorJson {
+            error: format!(
+                "Can't convert {} to {}, both units have to be one of {} and measure the same thing",
+                params.from, params.to, conversion_unit_names().join(", ")
+            ),
+        };
+        return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+    };
+
+    // rounded like the other computed values, to OUTPUT_PRECISION places or two by default
+    let scale = 10f64.powi(state.config.decode.precision.unwrap_or(2) as i32);
+    let body = ConvertJson {
+        value,
+        from,
+        to,
+        result: (result * scale).round() / scale,
+    };
+    Json(body).into_response()
+}
+
+// the json answer when the station has no current report, not an error but nothing to decode either
+pub fn no_report_json(config: &Config, icao: &str) -> Response {
+    let body = NoReportJson {
+        icao,
+        message: NoReport(icao.to_string()).to_string(),
+    };
+    (config.no_report_status, Json(body)).into_response()
+}
+
+// the previous and the latest decoded report, None when the last DIFF_HOURS have fewer than two
+pub async fn fetch_report_pair(state: &AppState, icao: &str) -> Result<Option<(MetarInfo, MetarInfo)>, Box<dyn std::error::Error>> {
+    let reports = fetch_metar_history(&state.config.metar_source_url, icao, DIFF_HOURS).await?;
+    let [latest, previous, ..] = reports.as_slice() else {
+        return Ok(None);
+    };
+    let options = &state.config.decode;
+    Ok(Some((parse_metar(previous, icao, options), parse_metar(latest, icao, options))))
+}
+
+// shown when there's only one report in the last few hours and nothing to compare it with
+pub fn single_report_message(icao: &str) -> String {
+    format!("Only one report for {} in the last {} hours, nothing to compare it with", icao, DIFF_HOURS)
+}
+
+// the flight category went down at least a step
+pub fn is_deteriorated(changes: &[FieldChange]) -> bool {
+    changes
+        .iter()
+        .any(|change| change.field == "flight_category" && change.delta.is_some_and(|delta| delta < 0.0))
+}
+
+// the source= override for a request when its host is in SOURCE_OVERRIDE_HOSTS, None to use METAR_SOURCE_URL.
+// overrides are refused outright when no hosts are allowed, and only plain http(s) urls without credentials pass
+pub fn request_source_url(config: &Config, source: Option<&str>) -> Result<Option<String>, String> {
+    let Some(source) = source.map(str::trim).filter(|source| !source.is_empty()) else {
+        return Ok(None);
+    };
+    if config.source_override_hosts.is_empty() {
+        return Err("Overriding the METAR source isn't enabled on this server".to_string());
+    }
+    let url = reqwest::Url::parse(source).map_err(|_| "The source isn't a valid URL".to_string())?;
+    let host_allowed = url
+        .host_str()
+        .is_some_and(|host| config.source_override_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)));
+    if !matches!(url.scheme(), "http" | "https") || !url.username().is_empty() || url.password().is_some() || !host_allowed {
+        return Err("The source isn't one of the allowed hosts".to_string());
+    }
+    Ok(Some(url.to_string()))
+}
+
+// the decoded report for a page or json request and whether it's a stale one. a report from a source= override
+// goes around the shared cache and is never kept for the stale fallback, so it can't be served to anyone asking
+// the configured upstream
+pub async fn fetch_request_info(
+    state: &AppState,
+    source_url: Option<&str>,
+    icao: &str,
+) -> Result<(MetarInfo, bool), Box<dyn std::error::Error>> {
+    if let Some(source_url) = source_url {
+        let metar = fetch_override_metar(source_url, icao).await?;
+        return Ok((parse_metar(&metar, icao, &state.config.decode), false));
+    }
+
+    let source_url = &state.config.metar_source_url;
+    match fetch_metar(source_url, icao).await {
+        Ok(metar) => Ok((state.decoded.decode(source_url, &metar, icao, &state.config.decode), false)),
+        Err(e) if e.is::<NoReport>() => Err(e),
+        Err(e) => match stale_fallback(state, source_url, icao, &e.to_string()) {
+            Some(info) => Ok((info, true)),
+            None => Err(e),
+        },
+    }
+}
+
+// handles requests for the decoded values as shell variables, for sourcing in a script
+pub async fn env_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MetarQuery>,
+) -> impl IntoResponse {
+    let (_, info, stale) = match fetch_api_info(&state, &params.icao).await {
+        Ok(fetched) => fetched,
+        Err(response) => return response,
+    };
+
+    // a missing value becomes an empty string, so scripts can test for it with -z
+    fn or_empty<T: ToString>(value: Option<T>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_default()
+    }
+    let variables = [
+        ("METAR_STATION", info.station.clone()),
+        ("METAR_DAY", or_empty(info.zulu_day)),
+        ("METAR_HOUR", or_empty(info.zulu_hour)),
+        ("METAR_MINUTE", or_empty(info.zulu_minute)),
+        ("METAR_WIND_DIR", or_empty(info.wind_dir_degrees)),
+        ("METAR_WIND_KT", or_empty(info.wind_speed_knots)),
+        ("METAR_WIND_GUST_KT", or_empty(info.wind_gust_knots)),
+        ("METAR_VISIBILITY_SM", or_empty(info.visibility_miles.map(|v| format_decimal(v as f64, 2)))),
+        ("METAR_CEILING_FT", or_empty(info.ceiling_feet)),
+        ("METAR_TEMP_C", or_empty(info.temperature_c)),
+        ("METAR_DEWPOINT_C", or_empty(info.dewpoint_c)),
+        ("METAR_ALTIMETER_INHG", or_empty(info.altimeter_inhg.map(|v| format!("{:.2}", v)))),
+        ("METAR_ALTIMETER_HPA", or_empty(info.altimeter_hpa)),
+        ("METAR_HUMIDITY", or_empty(info.humidity_percent)),
+        ("METAR_FEELS_LIKE_C", or_empty(info.feels_like_c)),
+        ("METAR_CATEGORY", info.flight_category.clone().unwrap_or_default()),
+        ("METAR_LIMITING_FACTOR", info.limiting_factor.clone().unwrap_or_default()),
+        ("METAR_RAW", info.raw.clone()),
+        ("METAR_STALE", if stale { "1" } else { "" }.to_string()),
+    ];
+    let body: String = variables
+        .iter()
+        .map(|(name, value)| format!("{}={}\n", name, shell_quote(value)))
+        .collect();
+
+    let headers = [
+        (header::CONTENT_TYPE.as_str(), "text/plain; charset=utf-8".to_string()),
+        (PARSER_VERSION_HEADER, PARSER_VERSION.to_string()),
+        (header::CACHE_CONTROL.as_str(), response_cache_control(&info, stale)),
+    ];
+    (headers, body).into_response()
+}
+
+// the shared start of the small api endpoints: checks the code and fetches and decodes the report,
+// falling back to the last one with STALE_ON_ERROR. gives back the normalized code and whether the report is
+// that stale fallback, or the error response
+pub async fn fetch_api_info(state: &AppState, icao: &str) -> Result<(String, MetarInfo, bool), Response> {
+    let icao = icao.trim().to_uppercase();
+
+    if !is_valid_station_code(&icao) {
+        let error = ErrorJson {
+            error: INVALID_CODE_MESSAGE.to_string(),
+        };
+        return Err((StatusCode::BAD_REQUEST, Json(error)).into_response());
+    }
+
+    match fetch_metar(&state.config.metar_source_url, &icao).await {
+        Ok(metar) => {
+            let info = state.decoded.decode(&state.config.metar_source_url, &metar, &icao, &state.config.decode);
+            Ok((icao, info, false))
         }
+        Err(e) if e.is::<NoReport>() => Err(no_report_json(&state.config, &icao)),
+        Err(e) => match stale_fallback(state, &state.config.metar_source_url, &icao, &e.to_string()) {
+            Some(info) => Ok((icao, info, true)),
+            None => {
+                let error = ErrorJson {
+                    error: format!("Error fetching METAR: {}", e),
+                };
+                Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error)).into_response())
+            }
+        },
+    }
+}
+
+// with MIN_DECODE_COMPLETENESS set, whether too little of the report decoded to be worth returning
+pub fn is_undecodable(info: &MetarInfo, config: &Config) -> bool {
+    config
+        .min_decode_completeness
+        .is_some_and(|min| info.decode_completeness < min)
+}
+
+// with STALE_ON_ERROR set, the last report decoded for the station when the upstream fetch fails.
+// only one fetched from the same upstream, so a report from a source= override never stands in for the usual one
+pub fn stale_fallback(state: &AppState, source_url: &str, icao: &str, error: &str) -> Option<MetarInfo> {
+    if !state.config.stale_on_error {
+        return None;
+    }
+    let info = state.decoded.last(source_url, icao)?;
+    eprintln!("Upstream fetch for {} failed ({}), serving the last report instead", icao, error);
+    Some(info)
+}
+
+// a stale report is only cached briefly so clients pick up the fresh one once the upstream is back
+pub fn response_cache_control(info: &MetarInfo, stale: bool) -> String {
+    if stale {
+        format!("public, max-age={}", MIN_CACHE_SECONDS)
+    } else {
+        cache_control_value(info)
+    }
+}
+
+// lets clients cache a report until the next routine observation is due
+pub fn cache_control_value(info: &MetarInfo) -> String {
+    let max_age = match (info.zulu_hour, info.zulu_minute) {
+        (Some(hour), Some(minute)) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            cache_max_age(hour, minute, now)
+        }
+        _ => MIN_CACHE_SECONDS,
+    };
+    format!("public, max-age={}", max_age)
+}
+
+// the banners above the report: the upstream being down so the page shows the last report fetched,
+// and a report that mostly couldn't be decoded
+pub fn render_notices(options: &PageOptions) -> String {
+    let mut notices = Vec::new();
+    if options.stale {
+        notices.push(r#"    <p class="page-notice" id="stale-notice">The weather service couldn't be reached, this is the last report fetched and may be out of date.</p>"#);
+    }
+    if options.undecodable {
+        notices.push(r#"    <p class="page-notice" id="undecodable-notice">Most of this report couldn't be decoded, check the raw METAR below.</p>"#);
     }
+    notices.join("\n")
+}
+
+// builds the page footer, leaving out the disclaimer in embed mode and the credits only when configured to
+pub fn render_footer(options: &PageOptions) -> String {
+    let show_disclaimer = !options.embed;
+    let show_credits = !(options.embed && options.hide_credits);
+    if !show_disclaimer && !show_credits {
+        return String::new();
+    }
+
+    let mut html = String::from("    <footer>\n");
+    if show_disclaimer {
+        html.push_str(FOOTER_DISCLAIMER);
+        html.push('\n');
+    }
+    if show_credits {
+        html.push_str(FOOTER_CREDITS);
+        html.push('\n');
+    }
+    html.push_str("    </footer>");
+    html
+}
+
+// keeps embed mode on when the search form is submitted from an embedded page
+pub fn render_embed_input(options: &PageOptions) -> String {
+    if options.embed {
+        r#"        <input type="hidden" name="embed" value="1">"#.to_string()
+    } else {
+        String::new()
+    }
+}
+
+// builds the dropdown links for the popular airports list
+pub fn render_popular_airports(airports: &[PopularAirport]) -> String {
+    airports
+        .iter()
+        .map(|airport| {
+            let label = if airport.name.is_empty() {
+                escape_html(&airport.icao)
+            } else {
+                format!("{} ({})", escape_html(&airport.icao), escape_html(&airport.name))
+            };
+            format!(
+                r#"                    <a href="/metar?icao={}" class="dropdown-item" onclick="hideDropdown()">{}</a>"#,
+                escape_html(&airport.icao), label
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// builds the rows for values computed from the report, leaving out any that couldn't be worked out
+pub fn render_computed_rows(info: &MetarInfo) -> String {
+    let rows = [
+        ("Flight category", "category-value", info.flight_category.clone()),
+        ("Ceiling", "ceiling-value", info.ceiling_feet.map(|feet| format!("{} feet", feet))),
+        ("Humidity", "humidity-value", info.humidity_percent.map(|percent| format!("{}%", percent))),
+        ("Feels like", "feels-like-value", info.feels_like_c.map(|c| format!("{}°C ({}°F)", c, celsius_to_fahrenheit(c)))),
+        ("Density altitude", "density-altitude-value", info.density_altitude_feet.map(|feet| format!("{} feet", feet))),
+        ("Limiting factor", "limiting-factor-value", info.limiting_factor.clone()),
+    ];
+
+    rows.iter()
+        .filter_map(|(label, id, value)| {
+            let (class, value) = format_stat_value(value.as_deref()?, "N/A");
+            Some(format!(
+                r#"        <div class="stat-row">
+            <span class="stat-label">{}:</span>
+            <span class="stat-value{}" id="{}">{}</span>
+        </div>
+"#,
+                label, class, id, value
+            ))
+        })
+        .collect()
+}
+
+// builds a row for each value with a trend, like "Temperature trend: ↑ rising ▁▃▅█"
+pub fn render_trend_rows(trend: Option<&Trend>) -> String {
+    let Some(trend) = trend else {
+        return String::new();
+    };
+    let rows = [
+        ("Temperature trend", "temperature-trend-value", &trend.temperature),
+        ("Pressure trend", "pressure-trend-value", &trend.pressure),
+        ("Wind trend", "wind-trend-value", &trend.wind),
+    ];
+
+    rows.iter()
+        .filter_map(|(label, id, value)| {
+            let value = value.as_ref()?;
+            Some(format!(
+                r#"        <div class="stat-row">
+            <span class="stat-label">{}:</span>
+            <span class="stat-value" id="{}">{}</span>
+        </div>
+"#,
+                label, id, describe_trend(value)
+            ))
+        })
+        .collect()
+}
+
+// the arrow, direction and sparkline, the same text refreshMetar writes
+pub fn describe_trend(trend: &ValueTrend) -> String {
+    format!("{} {} {}", trend.arrow, trend.direction.label(), trend.sparkline)
+}
+
+// builds the refresh button and search form under the results, left out of the print view
+pub fn render_results_controls(station: &str, popular_airports_html: &str, embed_input_html: &str) -> String {
+    format!(
+        r#"    <form class="action-buttons" action="/metar" method="get">
+        <input type="hidden" name="icao" value="{}">
+{}
+        <button type="submit" id="refresh-btn" onclick="refreshMetar(); return false;">Refresh</button>
+        <a href="/metar?icao={}&amp;print=1" target="_blank">Print view</a>
+        <a href="/diff?icao={}">What changed</a>
+        <span class="updated-ago" id="updated-ago"></span>
+    </form>
+
+    <p class="back-link"><a href="/">Back to home</a></p>
+
+    <form action="/metar" method="get" id="metar-form" onsubmit="handleSubmit(event)">
+        <label for="icao">Enter airport ICAO code:</label>
+        <div class="search-container">
+            <input type="text" id="icao" name="icao" placeholder="e.g., KJFK" maxlength="4" required 
+                   oninput="this.value = this.value.toUpperCase()"
+                   onfocus="showDropdown()"
+                   onblur="setTimeout(() => hideDropdown(), 200)"
+                   onkeypress="if(event.key === 'Enter') {{ event.preventDefault(); handleSubmit(event); }}">
+            <div id="dropdown" class="dropdown">
+                <div class="dropdown-section">
+                    <h4>Popular Airports</h4>
+{}
+                </div>
+                <div class="dropdown-section">
+                    <h4>Recent Searches</h4>
+                    <div id="recent-dropdown-list"></div>
+                </div>
+            </div>
+        </div>
+{}
+        <button type="submit" id="submit-btn">
+            Fetch METAR
+        </button>
+    </form>"#,
+        escape_html(station), embed_input_html,
+        escape_html(station),
+        escape_html(station),
+        popular_airports_html,
+        embed_input_html
+    )
 }
 
 // builds the html page that shows all the parsed metar information
-pub fn format_results_page(info: &MetarInfo) -> String {
+pub fn format_results_page(info: &MetarInfo, popular_airports: &[PopularAirport], options: &PageOptions) -> String {
+    let popular_airports_html = render_popular_airports(popular_airports);
+    let footer_html = render_footer(options);
+    let embed_input_html = render_embed_input(options);
+    let computed_rows_html = render_computed_rows(info) + &render_trend_rows(options.trend.as_ref());
+    let notices_html = render_notices(options);
+    // always rendered so refreshMetar can show or hide it when a special report comes or goes
+    let special_badge_html = format!(
+        r#"            <span class="special-badge" id="special-badge"{}>Special report (conditions changing)</span>"#,
+        if info.is_special { "" } else { " hidden" }
+    );
+
+    // the print view is a static page, so the buttons and search form aren't rendered at all
+    let (dark_mode_toggle_html, datetime_toggle_html, altimeter_toggle_html, copy_button_html, controls_html) = if options.print {
+        (String::new(), String::new(), String::new(), String::new(), String::new())
+    } else {
+        (
+            r#"    <button class="dark-mode-toggle" id="dark-mode-toggle" onclick="toggleDarkMode()">Dark Mode</button>"#.to_string(),
+            r#"            <button id="datetime-toggle" onclick="toggleDateTime()" style="font-size: 11px; padding: 2px 6px; margin-left: 10px; display: none;">Show Local</button>"#.to_string(),
+            r#"            <button id="altimeter-toggle" onclick="toggleAltimeter()" style="font-size: 11px; padding: 2px 6px; margin-left: 10px; display: none;">Toggle</button>"#.to_string(),
+            r#"            <button onclick="copyMetar()">Copy</button>"#.to_string(),
+            render_results_controls(&info.station, &popular_airports_html, &embed_input_html),
+        )
+    };
+
     let (dt_class, dt_value) = format_stat_value(&info.date_time, "N/A");
     let (wind_class, wind_value) = format_stat_value(&info.wind, "N/A");
     let (vis_class, vis_value) = format_stat_value(&info.visibility, "N/A");
@@ -73,7 +917,7 @@ pub fn format_results_page(info: &MetarInfo) -> String {
     } else {
         "null".to_string()
     };
-    let altimeter_inches = if let Some(inches) = info.altimeter_inches {
+    let altimeter_inhg = if let Some(inches) = info.altimeter_inhg {
         format!("{:.2}", inches)
     } else {
         "null".to_string()
@@ -176,6 +1020,14 @@ pub fn format_results_page(info: &MetarInfo) -> String {
             display: flex;
             gap: 10px;
             flex-wrap: wrap;
+            align-items: center;
+        }}
+        .updated-ago {{
+            font-size: 12px;
+            color: #666;
+        }}
+        body.dark-mode .updated-ago {{
+            color: #999;
         }}
         .search-container {{
             position: relative;
@@ -373,6 +1225,22 @@ pub fn format_results_page(info: &MetarInfo) -> String {
         #home-link {{
             color: #000;
         }}
+        .page-notice {{
+            border: 1px solid #ff0000;
+            color: #ff0000;
+            padding: 10px;
+        }}
+        #limiting-factor-value {{
+            color: #ff0000;
+            font-weight: bold;
+        }}
+        .special-badge {{
+            border: 1px solid #ff0000;
+            color: #ff0000;
+            font-size: 11px;
+            padding: 2px 6px;
+            margin-left: 10px;
+        }}
         body.dark-mode #home-link {{
             color: #e0e0e0;
         }}
@@ -415,98 +1283,82 @@ pub fn format_results_page(info: &MetarInfo) -> String {
                 width: auto;
             }}
         }}
+        @media print {{
+            body, body.dark-mode {{
+                margin: 0 auto;
+                background-color: #fff;
+                color: #000;
+            }}
+            body.dark-mode h1, body.dark-mode pre, body.dark-mode a, body.dark-mode footer .credits {{
+                color: #000;
+                border-color: #000;
+                background: #fff;
+            }}
+            .dark-mode-toggle, .action-buttons, .back-link, #metar-form, .stat-row button, .raw-metar-header button {{
+                display: none;
+            }}
+        }}
     </style>
 </head>
 <body>
-    <button class="dark-mode-toggle" id="dark-mode-toggle" onclick="toggleDarkMode()">Dark Mode</button>
+{}
     <h1><a href="/" id="home-link" style="text-decoration: none; cursor: pointer;">metarflow</a></h1>
-    
+{}
     <div class="stats">
         <div class="stat-row">
             <span class="stat-label">Airport:</span>
-            <span class="stat-value">{}</span>
+            <span class="stat-value" id="station-value">{}</span>
+{}
         </div>
         <div class="stat-row">
             <span class="stat-label">Date/Time:</span>
             <span class="stat-value{}" id="datetime-value">{}</span>
-            <button id="datetime-toggle" onclick="toggleDateTime()" style="font-size: 11px; padding: 2px 6px; margin-left: 10px; display: none;">Show Local</button>
+{}
         </div>
         <div class="stat-row">
             <span class="stat-label">Wind:</span>
-            <span class="stat-value{}">{}</span>
+            <span class="stat-value{}" id="wind-value">{}</span>
         </div>
         <div class="stat-row">
             <span class="stat-label">Visibility:</span>
-            <span class="stat-value{}">{}</span>
+            <span class="stat-value{}" id="visibility-value">{}</span>
         </div>
         <div class="stat-row">
             <span class="stat-label">Weather:</span>
-            <span class="stat-value{}">{}</span>
+            <span class="stat-value{}" id="weather-value">{}</span>
         </div>
         <div class="stat-row">
             <span class="stat-label">Clouds:</span>
-            <span class="stat-value{}">{}</span>
+            <span class="stat-value{}" id="clouds-value">{}</span>
         </div>
         <div class="stat-row">
             <span class="stat-label">Temperature:</span>
-            <span class="stat-value{}">{}</span>
+            <span class="stat-value{}" id="temperature-value">{}</span>
         </div>
         <div class="stat-row">
             <span class="stat-label">Dewpoint:</span>
-            <span class="stat-value{}">{}</span>
+            <span class="stat-value{}" id="dewpoint-value">{}</span>
         </div>
         <div class="stat-row">
             <span class="stat-label">Altimeter:</span>
             <span class="stat-value{}" id="altimeter-value">{}</span>
-            <button id="altimeter-toggle" onclick="toggleAltimeter()" style="font-size: 11px; padding: 2px 6px; margin-left: 10px; display: none;">Toggle</button>
+{}
         </div>
-        <div class="stat-row">
+{}        <div class="stat-row">
             <span class="stat-label">Remarks:</span>
-            <span class="stat-value{}">{}</span>
+            <span class="stat-value{}" id="remarks-value">{}</span>
         </div>
     </div>
 
     <div class="raw-metar">
         <div class="raw-metar-header">
             <h2>Raw METAR</h2>
-            <button onclick="copyMetar()">Copy</button>
+{}
         </div>
         <pre id="raw-metar-text">{}</pre>
     </div>
 
-    <div class="action-buttons">
-        <button onclick="window.location.reload()">Refresh</button>
-    </div>
-
-    <p class="back-link"><a href="/">Back to home</a></p>
-
-    <form action="/metar" method="get" id="metar-form" onsubmit="handleSubmit(event)">
-        <label for="icao">Enter airport ICAO code:</label>
-        <div class="search-container">
-            <input type="text" id="icao" name="icao" placeholder="e.g., KJFK" maxlength="4" required 
-                   oninput="this.value = this.value.toUpperCase()"
-                   onfocus="showDropdown()"
-                   onblur="setTimeout(() => hideDropdown(), 200)"
-                   onkeypress="if(event.key === 'Enter') {{ event.preventDefault(); handleSubmit(event); }}">
-            <div id="dropdown" class="dropdown">
-                <div class="dropdown-section">
-                    <h4>Popular Airports</h4>
-                    <a href="/metar?icao=KJFK" class="dropdown-item" onclick="hideDropdown()">KJFK (JFK)</a>
-                    <a href="/metar?icao=EGLL" class="dropdown-item" onclick="hideDropdown()">EGLL (Heathrow)</a>
-                    <a href="/metar?icao=KLAX" class="dropdown-item" onclick="hideDropdown()">KLAX (LAX)</a>
-                    <a href="/metar?icao=KORD" class="dropdown-item" onclick="hideDropdown()">KORD (O'Hare)</a>
-                    <a href="/metar?icao=EDDF" class="dropdown-item" onclick="hideDropdown()">EDDF (Frankfurt)</a>
-                </div>
-                <div class="dropdown-section">
-                    <h4>Recent Searches</h4>
-                    <div id="recent-dropdown-list"></div>
-                </div>
-            </div>
-        </div>
-        <button type="submit" id="submit-btn">
-            Fetch METAR
-        </button>
-    </form>
+{}
     <script>
         (function() {{
             const urlParams = new URLSearchParams(window.location.search);
@@ -540,6 +1392,7 @@ pub fn format_results_page(info: &MetarInfo) -> String {
         function updateRecentSearches() {{
             const recent = JSON.parse(localStorage.getItem('metarflow_recent') || '[]');
             const list = document.getElementById('recent-dropdown-list');
+            if (!list) return;
             list.innerHTML = '';
             
             const recentLimited = recent.slice(0, 5);
@@ -578,91 +1431,169 @@ pub fn format_results_page(info: &MetarInfo) -> String {
         
         updateRecentSearches();
         
-        (function() {{
-            const altimeterHpaStr = '{}';
-            const altimeterInchesStr = '{}';
-            const altimeterUnit = '{}';
+        // values the toggles work from, refreshMetar swaps in fresh ones
+        const metarState = {{
+            altimeterHpa: parseInt('{}'),
+            altimeterInches: parseFloat('{}'),
+            altimeterUnit: '{}',
+            currentUnit: '{}',
+            zuluDay: parseInt('{}'),
+            zuluHour: parseInt('{}'),
+            zuluMinute: parseInt('{}'),
+        }};
+        
+        function initAltimeterToggle() {{
+            const toggleBtn = document.getElementById('altimeter-toggle');
+            if (!toggleBtn) return;
             
-            if (altimeterHpaStr !== 'null' && altimeterInchesStr !== 'null') {{
-                const altimeterHpa = parseInt(altimeterHpaStr);
-                const altimeterInches = parseFloat(altimeterInchesStr);
-                let currentUnit = altimeterUnit;
+            if (isNaN(metarState.altimeterHpa) || isNaN(metarState.altimeterInches)) {{
+                toggleBtn.style.display = 'none';
+                return;
+            }}
             
-                if (!isNaN(altimeterHpa) && !isNaN(altimeterInches)) {{
-                    const toggleBtn = document.getElementById('altimeter-toggle');
-                    if (toggleBtn) {{
-                        toggleBtn.style.display = 'inline-block';
-                        if (altimeterUnit === 'hpa') {{
-                            toggleBtn.textContent = 'Show inHg';
-                        }} else {{
-                            toggleBtn.textContent = 'Show hPa';
-                        }}
-                    }}
-                }}
-                
-                window.toggleAltimeter = function() {{
-                    const valueEl = document.getElementById('altimeter-value');
-                    const toggleBtn = document.getElementById('altimeter-toggle');
-                    
-                    if (currentUnit === 'hpa') {{
-                        valueEl.textContent = altimeterInches.toFixed(2) + ' inches of mercury';
-                        currentUnit = 'inches';
-                        if (toggleBtn) toggleBtn.textContent = 'Show hPa';
-                    }} else {{
-                        valueEl.textContent = altimeterHpa + ' hectopascals';
-                        currentUnit = 'hpa';
-                        if (toggleBtn) toggleBtn.textContent = 'Show inHg';
-                    }}
-                }};
+            metarState.currentUnit = metarState.altimeterUnit;
+            toggleBtn.style.display = 'inline-block';
+            if (metarState.altimeterUnit === 'hpa') {{
+                toggleBtn.textContent = 'Show inHg';
+            }} else {{
+                toggleBtn.textContent = 'Show hPa';
             }}
-        }})();
+        }}
         
-        (function() {{
-            const zuluDayStr = '{}';
-            const zuluHourStr = '{}';
-            const zuluMinuteStr = '{}';
+        function toggleAltimeter() {{
+            const valueEl = document.getElementById('altimeter-value');
+            const toggleBtn = document.getElementById('altimeter-toggle');
             
-            if (zuluDayStr !== 'null' && zuluHourStr !== 'null' && zuluMinuteStr !== 'null') {{
-                const zuluDay = parseInt(zuluDayStr);
-                const zuluHour = parseInt(zuluHourStr);
-                const zuluMinute = parseInt(zuluMinuteStr);
+            if (metarState.currentUnit === 'hpa') {{
+                valueEl.textContent = metarState.altimeterInches.toFixed(2) + ' inches of mercury';
+                metarState.currentUnit = 'inches';
+                if (toggleBtn) toggleBtn.textContent = 'Show hPa';
+            }} else {{
+                valueEl.textContent = metarState.altimeterHpa + ' hectopascals';
+                metarState.currentUnit = 'hpa';
+                if (toggleBtn) toggleBtn.textContent = 'Show inHg';
+            }}
+        }}
+        
+        function initDateTimeToggle() {{
+            const toggleBtn = document.getElementById('datetime-toggle');
+            if (!toggleBtn) return;
+            
+            if (isNaN(metarState.zuluDay) || isNaN(metarState.zuluHour) || isNaN(metarState.zuluMinute)) {{
+                toggleBtn.style.display = 'none';
+                return;
+            }}
+            
+            toggleBtn.style.display = 'inline-block';
+            toggleBtn.textContent = 'Show Local';
+        }}
+        
+        function toggleDateTime() {{
+            const valueEl = document.getElementById('datetime-value');
+            const toggleBtn = document.getElementById('datetime-toggle');
+            
+            if (!valueEl || !toggleBtn) return;
+            
+            const zuluDay = metarState.zuluDay;
+            const zuluHour = metarState.zuluHour;
+            const zuluMinute = metarState.zuluMinute;
+            const isZulu = valueEl.textContent.includes('Z');
+            
+            if (isZulu) {{
+                const now = new Date();
+                const year = now.getFullYear();
+                const month = now.getMonth();
+                
+                const utcDate = new Date(Date.UTC(year, month, zuluDay, zuluHour, zuluMinute));
                 
-                if (!isNaN(zuluDay) && !isNaN(zuluHour) && !isNaN(zuluMinute)) {{
-                    const toggleBtn = document.getElementById('datetime-toggle');
-                    if (toggleBtn) {{
-                        toggleBtn.style.display = 'inline-block';
+                const localDay = utcDate.getDate();
+                const localHour = utcDate.getHours();
+                const localMinute = utcDate.getMinutes();
+                
+                valueEl.textContent = `Day ${{localDay}}, ${{localHour}}:${{String(localMinute).padStart(2, '0')}} Local`;
+                toggleBtn.textContent = 'Show Zulu';
+            }} else {{
+                valueEl.textContent = `Day ${{zuluDay}}, ${{String(zuluHour).padStart(2, '0')}}:${{String(zuluMinute).padStart(2, '0')}}Z`;
+                toggleBtn.textContent = 'Show Local';
+            }}
+        }}
+        
+        initAltimeterToggle();
+        initDateTimeToggle();
+        
+        let lastUpdated = Date.now();
+        
+        function updateAgo() {{
+            const el = document.getElementById('updated-ago');
+            if (!el) return;
+            const seconds = Math.round((Date.now() - lastUpdated) / 1000);
+            el.textContent = `Updated ${{seconds}}s ago`;
+        }}
+        
+        function setStat(id, value, fallback) {{
+            const el = document.getElementById(id);
+            if (!el) return;
+            el.textContent = value || fallback;
+            el.classList.toggle('empty', !value);
+        }}
+        
+        // fetches the json and updates the fields in place, falling back to a full reload if that fails
+        function refreshMetar() {{
+            const params = new URLSearchParams(window.location.search);
+            const icao = params.get('icao') || '';
+            const source = params.get('source');
+            const btn = document.getElementById('refresh-btn');
+            btn.disabled = true;
+            
+            fetch(`/api/metar?icao=${{encodeURIComponent(icao)}}` + (source ? `&source=${{encodeURIComponent(source)}}` : ''))
+                .then(response => {{
+                    if (!response.ok) throw new Error(response.status);
+                    return response.json();
+                }})
+                .then(data => {{
+                    setStat('station-value', data.station, 'N/A');
+                    document.getElementById('special-badge').hidden = !data.is_special;
+                    setStat('datetime-value', data.date_time, 'N/A');
+                    setStat('wind-value', data.wind, 'N/A');
+                    setStat('visibility-value', data.visibility, 'N/A');
+                    setStat('weather-value', data.weather, 'N/A');
+                    setStat('clouds-value', data.clouds, 'N/A');
+                    setStat('temperature-value', data.temperature, 'N/A');
+                    setStat('dewpoint-value', data.dewpoint, 'N/A');
+                    setStat('altimeter-value', data.altimeter, 'N/A');
+                    setStat('category-value', data.flight_category, 'N/A');
+                    setStat('ceiling-value', data.ceiling_feet === null ? '' : `${{data.ceiling_feet}} feet`, 'N/A');
+                    setStat('humidity-value', data.humidity_percent === null ? '' : `${{data.humidity_percent}}%`, 'N/A');
+                    setStat('feels-like-value', data.feels_like_c === null ? '' : `${{data.feels_like_c}}°C (${{Math.trunc(data.feels_like_c * 9 / 5) + 32}}°F)`, 'N/A');
+                    setStat('density-altitude-value', data.density_altitude_feet === null ? '' : `${{data.density_altitude_feet}} feet`, 'N/A');
+                    setStat('limiting-factor-value', data.limiting_factor, 'N/A');
+                    setStat('remarks-value', data.remarks, 'None');
+                    if (data.trend) {{
+                        const describeTrend = trend => trend ? `${{trend.arrow}} ${{trend.direction}} ${{trend.sparkline}}` : '';
+                        setStat('temperature-trend-value', describeTrend(data.trend.temperature), 'N/A');
+                        setStat('pressure-trend-value', describeTrend(data.trend.pressure), 'N/A');
+                        setStat('wind-trend-value', describeTrend(data.trend.wind), 'N/A');
                     }}
+                    document.getElementById('raw-metar-text').textContent = data.raw;
                     
-                    window.toggleDateTime = function() {{
-                        const valueEl = document.getElementById('datetime-value');
-                        const toggleBtn = document.getElementById('datetime-toggle');
-                        
-                        if (!valueEl || !toggleBtn) return;
-                        
-                        const currentText = valueEl.textContent;
-                        const isZulu = currentText.includes('Z');
-                        
-                        if (isZulu) {{
-                            const now = new Date();
-                            const year = now.getFullYear();
-                            const month = now.getMonth();
-                            
-                            const utcDate = new Date(Date.UTC(year, month, zuluDay, zuluHour, zuluMinute));
-                            
-                            const localDay = utcDate.getDate();
-                            const localHour = utcDate.getHours();
-                            const localMinute = utcDate.getMinutes();
-                            
-                            valueEl.textContent = `Day ${{localDay}}, ${{localHour}}:${{String(localMinute).padStart(2, '0')}} Local`;
-                            toggleBtn.textContent = 'Show Zulu';
-                        }} else {{
-                            valueEl.textContent = `Day ${{zuluDay}}, ${{String(zuluHour).padStart(2, '0')}}:${{String(zuluMinute).padStart(2, '0')}}Z`;
-                            toggleBtn.textContent = 'Show Local';
-                        }}
-                    }};
-                }}
-            }}
-        }})();
+                    metarState.altimeterHpa = data.altimeter_hpa === null ? NaN : data.altimeter_hpa;
+                    metarState.altimeterInches = data.altimeter_inhg === null ? NaN : data.altimeter_inhg;
+                    metarState.altimeterUnit = data.altimeter_default_unit || 'hpa';
+                    metarState.zuluDay = data.zulu_day === null ? NaN : data.zulu_day;
+                    metarState.zuluHour = data.zulu_hour === null ? NaN : data.zulu_hour;
+                    metarState.zuluMinute = data.zulu_minute === null ? NaN : data.zulu_minute;
+                    initAltimeterToggle();
+                    initDateTimeToggle();
+                    
+                    lastUpdated = Date.now();
+                    updateAgo();
+                    btn.disabled = false;
+                }})
+                .catch(() => window.location.reload());
+        }}
+        
+        updateAgo();
+        setInterval(updateAgo, 1000);
         
         function copyMetar() {{
             const text = document.getElementById('raw-metar-text').textContent;
@@ -696,6 +1627,7 @@ pub fn format_results_page(info: &MetarInfo) -> String {
         function initDarkMode() {{
             const savedMode = localStorage.getItem('metarflow_dark_mode');
             const toggle = document.getElementById('dark-mode-toggle');
+            if (!toggle) return;
             
             if (savedMode === 'true') {{
                 document.body.classList.add('dark-mode');
@@ -709,26 +1641,665 @@ pub fn format_results_page(info: &MetarInfo) -> String {
         initDarkMode();
         
     </script>
-    <footer>
-        <p class="disclaimer">The information on this website is intended for educational purposes only. Not for operational use.</p>
-        <p class="credits">Created by <a href="https://github.com/Balionelis/metarflow" target="_blank" rel="noopener noreferrer"><svg class="github-icon" viewBox="0 0 16 16" fill="currentColor"><path d="M8 0C3.58 0 0 3.58 0 8c0 3.54 2.29 6.53 5.47 7.59.4.07.55-.17.55-.38 0-.19-.01-.82-.01-1.49-2.01.37-2.53-.49-2.69-.94-.09-.23-.48-.94-.82-1.13-.28-.15-.68-.52-.01-.53.63-.01 1.08.58 1.23.82.72 1.21 1.87.87 2.33.66.07-.52.28-.87.51-1.07-1.78-.2-3.64-.89-3.64-3.95 0-.87.31-1.59.82-2.15-.08-.2-.36-1.02.08-2.12 0 0 .67-.21 2.2.82.64-.18 1.32-.27 2-.27.68 0 1.36.09 2 .27 1.53-1.04 2.2-.82 2.2-.82.44 1.1.16 1.92.08 2.12.51.56.82 1.27.82 2.15 0 3.07-1.87 3.75-3.65 3.95.29.25.54.73.54 1.48 0 1.07-.01 1.93-.01 2.2 0 .21.15.46.55.38A8.012 8.012 0 0 0 16 8c0-4.42-3.58-8-8-8z"/></svg>Balionelis</a>. Licensed under <a href="https://github.com/Balionelis/metarflow/blob/main/LICENSE" target="_blank" rel="noopener noreferrer">GPL-3.0</a>. <a href="/privacy">Privacy</a>.</p>
-    </footer>
+{}
 </body>
 </html>
         "#,
-        info.station,
-        dt_class, dt_value,
+        dark_mode_toggle_html,
+        notices_html,
+        escape_html(&info.station),
+        special_badge_html,
+        dt_class, dt_value, datetime_toggle_html,
         wind_class, wind_value,
         vis_class, vis_value,
         wx_class, wx_value,
         clouds_class, clouds_value,
         temp_class, temp_value,
         dew_class, dew_value,
-        alt_class, alt_value,
+        alt_class, alt_value, altimeter_toggle_html,
+        computed_rows_html,
         rmk_class, rmk_value,
-        info.raw,
-        altimeter_hpa, altimeter_inches, altimeter_default,
-        zulu_day, zulu_hour, zulu_minute
+        copy_button_html,
+        escape_html(&info.raw),
+        controls_html,
+        altimeter_hpa, altimeter_inhg, altimeter_default, altimeter_default,
+        zulu_day, zulu_hour, zulu_minute,
+        footer_html
     )
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::DecodeOptions;
+    use crate::test_support;
+
+    #[test]
+    fn custom_popular_airports_render_in_the_dropdown() {
+        let airports = [
+            PopularAirport { icao: "EYVI".to_string(), name: "Vilnius".to_string() },
+            PopularAirport { icao: "EGLL".to_string(), name: String::new() },
+        ];
+        let html = render_popular_airports(&airports);
+        assert!(html.contains(r#"<a href="/metar?icao=EYVI" class="dropdown-item" onclick="hideDropdown()">EYVI (Vilnius)</a>"#));
+        assert!(html.contains(r#"<a href="/metar?icao=EGLL" class="dropdown-item" onclick="hideDropdown()">EGLL</a>"#));
+        assert!(!html.contains("KJFK"));
+    }
+
+    #[tokio::test]
+    async fn version_matches_the_crate() {
+        let app = test_support::app(Config::default());
+        let (status, _, body) = test_support::get(&app, "/version").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["git_hash"].is_string());
+        assert!(json["build_time"].is_string());
+    }
+
+    #[tokio::test]
+    async fn json_carries_the_parser_version_in_body_and_header() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (status, headers, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["parser_version"], PARSER_VERSION);
+        assert_eq!(headers[PARSER_VERSION_HEADER], PARSER_VERSION);
+        assert_eq!(json["station"], "KJFK");
+        assert_eq!(json["raw"], test_support::SAMPLE_METAR);
+    }
+
+    #[tokio::test]
+    async fn embed_mode_drops_the_disclaimer() {
+        let app = test_support::app(Config::default());
+        let (_, _, body) = test_support::get(&app, "/").await;
+        assert!(body.contains(r#"class="disclaimer""#));
+        assert!(body.contains(r#"class="credits""#));
+
+        let (_, _, body) = test_support::get(&app, "/?embed=1").await;
+        assert!(!body.contains(r#"class="disclaimer""#));
+        assert!(body.contains(r#"class="credits""#));
+
+        let config = Config {
+            hide_footer: true,
+            hide_credits: true,
+            ..Config::default()
+        };
+        let (_, _, body) = test_support::get(&test_support::app(config), "/").await;
+        assert!(!body.contains("<footer>"));
+    }
+
+    #[tokio::test]
+    async fn default_icao_redirects_the_index() {
+        let config = Config {
+            default_icao: Some("KJFK".to_string()),
+            ..Config::default()
+        };
+        let app = test_support::app(config);
+        let (status, headers, _) = test_support::get(&app, "/").await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(headers[header::LOCATION], "/metar?icao=KJFK");
+
+        let (status, _, body) = test_support::get(&test_support::app(Config::default()), "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<form"));
+    }
+
+    #[tokio::test]
+    async fn json_has_the_fields_the_refresh_script_reads() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (status, _, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        for field in [
+            "station", "is_special", "date_time", "wind", "visibility", "weather", "clouds", "temperature", "dewpoint",
+            "altimeter", "flight_category", "ceiling_feet", "humidity_percent", "feels_like_c", "density_altitude_feet",
+            "limiting_factor", "remarks", "raw", "altimeter_hpa", "altimeter_inhg", "altimeter_default_unit", "zulu_day", "zulu_hour",
+            "zulu_minute",
+        ] {
+            assert!(json.get(field).is_some(), "missing {}", field);
+        }
+        assert_eq!(json["raw"], test_support::SAMPLE_METAR);
+        assert_eq!(json["zulu_hour"], 15);
+    }
+
+    #[tokio::test]
+    async fn codes_with_url_characters_never_reach_the_upstream() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        // "KJ&F", "K/FK", "KJ K" and "KJFK&ids=EGLL"
+        for code in ["KJ%26F", "K%2FFK", "KJ%20K", "KJFK%26ids%3DEGLL"] {
+            let (status, _, body) = test_support::get(&app, &format!("/api/metar?icao={}", code)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", code);
+            assert!(body.contains(INVALID_CODE_MESSAGE));
+            let (status, _, _) = test_support::get(&app, &format!("/metar?icao={}", code)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", code);
+        }
+        assert_eq!(upstream.hits(), 0);
+
+        for code in ["KJ&F", "K/FK", "KJ K"] {
+            assert!(!is_valid_station_code(code));
+            assert!(crate::services::fetch_metar(&upstream.url, code).await.is_err());
+        }
+        assert_eq!(upstream.hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn print_view_omits_the_controls() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (_, _, body) = test_support::get(&app, "/metar?icao=KJFK").await;
+        for control in [r#"id="dark-mode-toggle""#, r#"id="refresh-btn""#, r#"id="metar-form""#, r#"id="dropdown""#, "copyMetar()"] {
+            assert!(body.contains(control), "{}", control);
+        }
+
+        let (_, _, body) = test_support::get(&app, "/metar?icao=KJFK&print=1").await;
+        for control in [r#"id="dark-mode-toggle""#, r#"id="refresh-btn""#, r#"id="metar-form""#, r#"id="dropdown""#, r#"onclick="copyMetar()""#] {
+            assert!(!body.contains(control), "{}", control);
+        }
+        assert!(body.contains(test_support::SAMPLE_METAR));
+    }
+
+    #[tokio::test]
+    async fn custom_privacy_file_is_served() {
+        let path = std::env::temp_dir().join(format!("metarflow-privacy-{}.html", std::process::id()));
+        std::fs::write(&path, "<h1>Run by Example Aero Club</h1>").unwrap();
+        // a variable only this test sets, so it can't race the others
+        std::env::set_var("METARFLOW_TEST_PRIVACY_FILE", &path);
+        let config = Config {
+            privacy_html: crate::config::read_file_from_env("METARFLOW_TEST_PRIVACY_FILE"),
+            ..Config::default()
+        };
+        std::fs::remove_file(&path).unwrap();
+
+        let (status, _, body) = test_support::get(&test_support::app(config), "/privacy").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "<h1>Run by Example Aero Club</h1>");
+
+        let (_, _, body) = test_support::get(&test_support::app(Config::default()), "/privacy").await;
+        assert!(body.contains("Privacy"));
+    }
+
+    #[tokio::test]
+    async fn robots_has_the_default_directives() {
+        let (status, headers, body) = test_support::get(&test_support::app(Config::default()), "/robots.txt").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/plain"));
+        assert!(body.contains("User-agent: *"));
+        assert!(body.contains("Allow: /$"));
+        assert!(body.contains("Disallow: /metar"));
+
+        let config = Config {
+            robots_txt: "User-agent: *\nDisallow: /\n".to_string(),
+            ..Config::default()
+        };
+        let (_, _, body) = test_support::get(&test_support::app(config), "/robots.txt").await;
+        assert_eq!(body, "User-agent: *\nDisallow: /\n");
+    }
+
+    #[test]
+    fn computed_rows_appear_when_computable() {
+        let info = parse_metar("KJFK 161551Z 28010KT 3SM BR BKN008 12/10 A2990", "KJFK", &DecodeOptions::default());
+        let rows = render_computed_rows(&info);
+        assert!(rows.contains(r#"<span class="stat-value" id="category-value">IFR</span>"#), "{}", rows);
+        assert!(rows.contains(r#"id="ceiling-value">800 feet</span>"#));
+        assert!(rows.contains(r#"id="humidity-value">"#));
+        assert!(rows.contains(r#"id="limiting-factor-value">ceiling</span>"#));
+
+        // nothing to compute a ceiling or humidity from
+        let info = parse_metar("KJFK 161551Z 28010KT 10SM A2990", "KJFK", &DecodeOptions::default());
+        let rows = render_computed_rows(&info);
+        assert!(!rows.contains("ceiling-value"));
+        assert!(!rows.contains("humidity-value"));
+    }
+
+    // an upstream that serves the sample report until told to fail with a 500
+    async fn failing_upstream() -> (String, Arc<std::sync::atomic::AtomicBool>) {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let failing = Arc::new(AtomicBool::new(false));
+        let flag = failing.clone();
+        let router = axum::Router::new().fallback(move || {
+            let failing = flag.load(Ordering::SeqCst);
+            async move {
+                if failing {
+                    (StatusCode::INTERNAL_SERVER_ERROR, "down").into_response()
+                } else {
+                    test_support::SAMPLE_METAR.into_response()
+                }
+            }
+        });
+        (test_support::spawn_server(router).await, failing)
+    }
+
+    #[tokio::test]
+    async fn last_report_is_served_stale_when_the_upstream_fails() {
+        let (url, failing) = failing_upstream().await;
+        let config = Config {
+            stale_on_error: true,
+            ..test_support::config_with_source(&url)
+        };
+        let app = test_support::app(config);
+        let (_, _, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert!(body.contains("\"stale\":false"));
+
+        failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        let (status, headers, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"stale\":true"));
+        assert!(body.contains(test_support::SAMPLE_METAR));
+        assert_eq!(headers[header::CACHE_CONTROL], format!("public, max-age={}", MIN_CACHE_SECONDS));
+
+        let (status, _, body) = test_support::get(&app, "/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#"id="stale-notice""#));
+
+        let (status, headers, body) = test_support::get(&app, "/api/category?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"stale\":true"));
+        assert_eq!(headers[header::CACHE_CONTROL], format!("public, max-age={}", MIN_CACHE_SECONDS));
+
+        let (status, headers, body) = test_support::get(&app, "/api/metar/env?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("METAR_STALE='1'\n"));
+        assert_eq!(headers[header::CACHE_CONTROL], format!("public, max-age={}", MIN_CACHE_SECONDS));
+
+        // nothing fetched yet for this one, so there's nothing to fall back to
+        let (status, _, _) = test_support::get(&app, "/api/metar?icao=KLAX").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn stale_fallback_is_off_by_default() {
+        let (url, failing) = failing_upstream().await;
+        let app = test_support::app(test_support::config_with_source(&url));
+        test_support::get(&app, "/api/metar?icao=KJFK").await;
+        failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        let (status, _, _) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn undecodable_report_is_a_422() {
+        let upstream = test_support::metar_upstream("KJFK 161551Z A2990").await;
+        let config = Config {
+            min_decode_completeness: Some(0.6),
+            ..test_support::config_with_source(&upstream.url)
+        };
+        let app = test_support::app(config);
+        let (status, _, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["error"], "Couldn't decode the report for KJFK");
+        assert_eq!(json["raw"], "KJFK 161551Z A2990");
+        assert_eq!(json["decode_completeness"], 0.2);
+        assert_eq!(json["missing_fields"], serde_json::json!(["wind", "visibility", "clouds", "temperature"]));
+
+        // the page still shows it, with a warning
+        let (status, _, body) = test_support::get(&app, "/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#"id="undecodable-notice""#));
+    }
+
+    #[tokio::test]
+    async fn dashboard_has_one_card_per_airport() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (status, _, body) = test_support::get(&app, "/dashboard?icaos=KJFK,klax,%20KORD").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.matches(r#"<div class="card" data-icao="#).count(), 3);
+        let kjfk = body.find(r#"data-icao="KJFK""#).unwrap();
+        let klax = body.find(r#"data-icao="KLAX""#).unwrap();
+        let kord = body.find(r#"data-icao="KORD""#).unwrap();
+        assert!(kjfk < klax && klax < kord);
+        assert_eq!(upstream.hits(), 3);
+
+        // the popular airports when none are given
+        let (_, _, body) = test_support::get(&app, "/dashboard").await;
+        assert_eq!(body.matches(r#"<div class="card" data-icao="#).count(), Config::default().popular_airports.len());
+
+        let too_many = vec!["KJFK"; MAX_DASHBOARD_AIRPORTS + 1].join(",");
+        let (status, _, _) = test_support::get(&app, &format!("/dashboard?icaos={}", too_many)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn dashboard_card_for_a_failed_airport() {
+        let card = render_dashboard_card("KXYZ", &Err("No current report for KXYZ".to_string()));
+        assert!(card.contains(r#"<span class="category">N/A</span>"#));
+        assert!(card.contains(r#"<div class="card-error">No current report for KXYZ</div>"#));
+    }
+
+    #[tokio::test]
+    async fn field_errors_only_when_asked_for() {
+        let upstream = test_support::metar_upstream("KJFK 161551Z 28010KT 10SM FEW040 XYZZY 12/M02 A2990").await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (_, _, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert!(!body.contains("field_errors"));
+
+        let (_, _, body) = test_support::get(&app, "/api/metar?icao=KJFK&field_errors=1").await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            json["field_errors"],
+            serde_json::json!([{"token": "XYZZY", "phase": "clouds", "reason": "Not a weather or cloud group"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn category_body_is_minimal() {
+        let upstream = test_support::metar_upstream("KJFK 161551Z 28010KT 4SM BR BKN020 12/10 A2990").await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (status, _, body) = test_support::get(&app, "/api/category?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "icao": "KJFK",
+                "category": "MVFR",
+                "vfr": false,
+                "mvfr": true,
+                "ifr": false,
+                "lifr": false,
+                "stale": false,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn source_override_only_when_enabled_and_allowed() {
+        let configured = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let other = test_support::metar_upstream("KJFK 161651Z 09005KT 3SM BR OVC009 10/09 A2985").await;
+        let uri = format!("/api/metar?icao=KJFK&source={}", other.url);
+
+        let app = test_support::app(test_support::config_with_source(&configured.url));
+        let (status, _, body) = test_support::get(&app, &uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("isn't enabled"));
+
+        let app = test_support::app(Config {
+            source_override_hosts: vec!["staging.example.com".to_string()],
+            ..test_support::config_with_source(&configured.url)
+        });
+        let (status, _, body) = test_support::get(&app, &uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("allowed hosts"));
+        assert_eq!(other.hits(), 0);
+
+        let app = test_support::app(Config {
+            source_override_hosts: vec!["127.0.0.1".to_string()],
+            ..test_support::config_with_source(&configured.url)
+        });
+        let (status, _, body) = test_support::get(&app, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("161651Z"));
+        assert_eq!(other.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn source_override_reports_stay_out_of_the_cache() {
+        let (url, failing) = failing_upstream().await;
+        let other = test_support::metar_upstream("KJFK 161651Z 09005KT 3SM BR OVC009 10/09 A2985").await;
+        let state = Arc::new(AppState::new(Config {
+            stale_on_error: true,
+            source_override_hosts: vec!["127.0.0.1".to_string()],
+            ..test_support::config_with_source(&url)
+        }));
+        let app = crate::routes::create_router(state.clone());
+
+        failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        let (status, _, _) = test_support::get(&app, &format!("/api/metar?icao=KJFK&source={}", other.url)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(state.decoded.last(&other.url, "KJFK").is_none());
+        assert!(state.decoded.last(&url, "KJFK").is_none());
+
+        // with nothing from the configured upstream to fall back on, the failure shows
+        let (status, _, _) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // a source url nothing is listening on
+    async fn closed_port_url() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn empty_body_is_no_report_and_a_dead_upstream_is_an_error() {
+        let empty = test_support::metar_upstream("").await;
+        let app = test_support::app(test_support::config_with_source(&empty.url));
+        let (status, _, body) = test_support::get(&app, "/metar?icao=JFK").await;
+        assert_eq!(status, Config::default().no_report_status);
+        assert!(body.contains("No current report for JFK."));
+        assert!(body.contains(FOOTER_DISCLAIMER));
+        assert!(!body.contains("{{FOOTER}}"));
+        assert!(!body.contains("maxlength"));
+
+        let (_, _, body) = test_support::get(&app, "/metar?icao=JFK&embed=1").await;
+        assert!(!body.contains(FOOTER_DISCLAIMER));
+
+        let app = test_support::app(test_support::config_with_source(&closed_port_url().await));
+        let (status, _, body) = test_support::get(&app, "/metar?icao=JFK").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("Error fetching METAR"));
+        assert!(!body.contains("No current report"));
+    }
+
+    #[tokio::test]
+    async fn env_output_for_a_known_report() {
+        let upstream = test_support::metar_upstream("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK O'HARE").await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (status, headers, body) = test_support::get(&app, "/api/metar/env?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        let expected = "\
+METAR_STATION='KJFK'
+METAR_DAY='16'
+METAR_HOUR='15'
+METAR_MINUTE='51'
+METAR_WIND_DIR='280'
+METAR_WIND_KT='10'
+METAR_WIND_GUST_KT=''
+METAR_VISIBILITY_SM='10.00'
+METAR_CEILING_FT=''
+METAR_TEMP_C='12'
+METAR_DEWPOINT_C='-2'
+METAR_ALTIMETER_INHG='29.90'
+METAR_ALTIMETER_HPA='1013'
+METAR_HUMIDITY='38'
+METAR_FEELS_LIKE_C=''
+METAR_CATEGORY='VFR'
+METAR_LIMITING_FACTOR=''
+METAR_RAW='KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK O'\\''HARE'
+METAR_STALE=''
+";
+        assert_eq!(body, expected);
+    }
+
+    #[tokio::test]
+    async fn speci_badge_and_json_flag() {
+        let upstream = test_support::metar_upstream("SPECI KJFK 161612Z 28010KT 2SM BR OVC008 12/10 A2990").await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+
+        let (_, _, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["is_special"], true);
+
+        let (_, _, html) = test_support::get(&app, "/metar?icao=KJFK").await;
+        assert!(html.contains(r#"id="special-badge">Special report (conditions changing)</span>"#));
+    }
+
+    #[tokio::test]
+    async fn error_page_escapes_the_message() {
+        let response = render_error(StatusCode::BAD_GATEWAY, r#"<script>alert("x" & 'y')</script>"#);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let html = String::from_utf8_lossy(&bytes);
+        assert!(html.contains("&lt;script&gt;alert(&quot;x&quot; &amp; &#39;y&#39;)&lt;/script&gt;"));
+        assert!(!html.contains("<script>alert"));
+    }
+
+    #[tokio::test]
+    async fn results_page_escapes_the_report() {
+        let upstream = test_support::metar_upstream(r#"KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK <img src=x onerror="a&b">'"#).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (status, _, html) = test_support::get(&app, "/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(html.contains(r#"<pre id="raw-metar-text">KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK &lt;img src=x onerror=&quot;a&amp;b&quot;&gt;&#39;</pre>"#));
+        assert!(html.contains(r#"id="remarks-value">&lt;IMG SRC=X ONERROR=&quot;A&amp;B&quot;&gt;&#39;</span>"#));
+        assert!(!html.to_lowercase().contains("<img src=x"));
+    }
+
+    #[tokio::test]
+    async fn convert_endpoint_results_and_errors() {
+        let app = test_support::app(Config::default());
+        let (status, _, body) = test_support::get(&app, "/api/convert?value=29.92&from=inHg&to=hPa").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"value":29.92,"from":"inhg","to":"hpa","result":1013.21}"#);
+
+        let (_, _, body) = test_support::get(&app, "/api/convert?value=100&from=km/h&to=kt").await;
+        assert!(body.contains(r#""result":54.0"#));
+
+        let (status, _, body) = test_support::get(&app, "/api/convert?value=1&from=kt&to=c").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("Can't convert kt to c"));
+
+        let (status, headers, body) = test_support::get(&app, "/api/convert?value=abc&from=inhg&to=hpa").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body, r#"{"error":"Can't convert \"abc\", the value has to be a number"}"#);
+    }
+
+    // an upstream with seven hourly reports, newest first
+    async fn seven_report_upstream() -> test_support::Upstream {
+        let reports: Vec<String> = (0..7)
+            .map(|i| format!("KJFK 16{:02}51Z 28010KT 10SM FEW040 12/M02 A2990", 15 - i))
+            .collect();
+        test_support::metar_upstream(&reports.join("\n")).await
+    }
+
+    // the history json for the query, with the decoded observation times pulled out
+    async fn history_page(app: &axum::Router, query: &str) -> (StatusCode, serde_json::Value, Vec<String>) {
+        let (status, _, body) = test_support::get(app, &format!("/api/metar/history?icao=KJFK&{}", query)).await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let times = json["observations"]
+            .as_array()
+            .map(|observations| observations.iter().map(|o| o["date_time"].as_str().unwrap().to_string()).collect())
+            .unwrap_or_default();
+        (status, json, times)
+    }
+
+    #[tokio::test]
+    async fn history_pagination_boundaries() {
+        let upstream = seven_report_upstream().await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+
+        let (status, json, times) = history_page(&app, "per_page=3").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((json["page"].as_u64(), json["total"].as_u64(), json["total_pages"].as_u64()), (Some(1), Some(7), Some(3)));
+        assert_eq!(times, ["Day 16, 15:51Z", "Day 16, 14:51Z", "Day 16, 13:51Z"]);
+
+        let (_, _, times) = history_page(&app, "page=3&per_page=3").await;
+        assert_eq!(times, ["Day 16, 9:51Z"]);
+
+        let (status, json, times) = history_page(&app, "page=4&per_page=3").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(times.is_empty());
+        assert_eq!(json["total"], 7);
+
+        let (status, _, times) = history_page(&app, &format!("page={}&per_page=24", usize::MAX)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(times.is_empty());
+
+        for query in ["page=0", "per_page=0", "per_page=25", "hours=0"] {
+            let (status, json, _) = history_page(&app, query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+            assert!(json["error"].is_string(), "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn trend_shows_on_the_page_and_in_the_json() {
+        // the latest report, or the last few hours when asked with hours=
+        let router = axum::Router::new().fallback(|axum::extract::RawQuery(query): axum::extract::RawQuery| async move {
+            if query.unwrap_or_default().contains("hours=") {
+                "KJFK 161551Z 28010KT 10SM FEW040 14/M02 A2990\nKJFK 161451Z 28010KT 10SM FEW040 12/M02 A2990\nKJFK 161351Z 28010KT 10SM FEW040 10/M02 A2990"
+            } else {
+                "KJFK 161551Z 28010KT 10SM FEW040 14/M02 A2990"
+            }
+        });
+        let url = test_support::spawn_server(router).await;
+        let config = Config {
+            show_trends: true,
+            ..test_support::config_with_source(&url)
+        };
+        let app = test_support::app(config);
+
+        let (_, _, body) = test_support::get(&app, "/metar?icao=KJFK").await;
+        assert!(body.contains(r#"<span class="stat-value" id="temperature-trend-value">↑ rising ▁▅█</span>"#));
+        assert!(body.contains(r#"id="pressure-trend-value">→ steady ▄▄▄</span>"#));
+
+        let (_, _, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["trend"]["observations"], 3);
+        assert_eq!(json["trend"]["temperature"]["direction"], "rising");
+        assert_eq!(json["trend"]["temperature"]["arrow"], "↑");
+
+        // off by default
+        let app = test_support::app(test_support::config_with_source(&url));
+        let (_, _, body) = test_support::get(&app, "/api/metar?icao=KJFK").await;
+        assert!(!body.contains("\"trend\""));
+    }
+
+    // an upstream whose history is the two reports, newest first
+    async fn history_upstream(latest: &str, previous: &str) -> test_support::Upstream {
+        test_support::metar_upstream(&format!("{}\n{}", latest, previous)).await
+    }
+
+    #[tokio::test]
+    async fn diff_json_and_page() {
+        let upstream = history_upstream(
+            "KJFK 161551Z 30018G28KT 2SM BR OVC008 12/10 A2990",
+            "KJFK 161451Z 28010KT 10SM FEW040 12/M02 A2990",
+        )
+        .await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+
+        let (status, _, body) = test_support::get(&app, "/api/metar/diff?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["latest_raw"], "KJFK 161551Z 30018G28KT 2SM BR OVC008 12/10 A2990");
+        assert_eq!(json["deteriorated"], true);
+        assert_eq!(json["changes"][0]["field"], "wind");
+        assert_eq!(json["changes"][0]["changed"], true);
+        assert_eq!(json["changes"][0]["delta"], 8.0);
+        assert_eq!(json["changes"][3]["before"], "VFR");
+        assert_eq!(json["changes"][3]["after"], "IFR");
+        assert_eq!(json["changes"][3]["delta"], -2.0);
+
+        let (status, _, body) = test_support::get(&app, "/diff?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<tr class=\"changed\">\n            <td>Wind</td>"));
+        assert!(body.contains("<td class=\"delta\">+8 kt</td>"));
+        assert!(body.contains("<td class=\"delta\">worse</td>"));
+    }
+
+    #[tokio::test]
+    async fn diff_needs_two_reports() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (status, _, body) = test_support::get(&app, "/api/metar/diff?icao=KJFK").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("nothing to compare"));
+
+        let upstream = history_upstream(test_support::SAMPLE_METAR, test_support::SAMPLE_METAR).await;
+        let app = test_support::app(test_support::config_with_source(&upstream.url));
+        let (_, _, body) = test_support::get(&app, "/diff?icao=KJFK").await;
+        assert!(!body.contains("<tr class=\"changed\">"));
+        assert_eq!(body.matches("<tr class=\"unchanged\">").count(), 4);
+    }
+}
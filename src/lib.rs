@@ -1,5 +1,14 @@
+pub mod alerts;
+pub mod cache;
+pub mod config;
+#[cfg(feature = "graphql")]
+pub mod graphql;
 pub mod handlers;
+pub mod middleware;
 pub mod models;
 pub mod routes;
+pub mod server;
 pub mod services;
+#[cfg(test)]
+pub mod test_support;
 pub mod utils;
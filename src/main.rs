@@ -1,16 +1,60 @@
 use std::sync::Arc;
+use metarflow::alerts::run_category_alert;
+use metarflow::config::Config;
 use metarflow::models::AppState;
 use metarflow::routes::create_router;
+use metarflow::server::serve;
+use metarflow::services::check_upstream;
 
 #[tokio::main]
 async fn main() {
-    let state = Arc::new(AppState {});
-    
+    let mut config = Config::from_env();
+    if std::env::args().any(|arg| arg == "--check-upstream") {
+        config.check_upstream = true;
+    }
+
+    // optional self-test so a bad METAR_SOURCE_URL fails at boot rather than on the first request
+    if config.check_upstream {
+        match check_upstream(&config.metar_source_url, &config.decode).await {
+            Ok(()) => println!("Upstream check passed for {}", config.metar_source_url),
+            Err(e) => {
+                eprintln!("Upstream check failed for {}: {}", config.metar_source_url, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if config.force_https && config.public_host.is_none() {
+        eprintln!("FORCE_HTTPS is set without PUBLIC_HOST, so plain http requests won't be redirected");
+    }
+
+    let listen_uds = config.listen_uds.clone();
+    let category_alert = config.category_alert.clone();
+    let state = Arc::new(AppState::new(config));
+
+    if let Some(alert) = category_alert {
+        println!("Alerting {} when {} drops below {}", alert.webhook_url, alert.icao, alert.below);
+        tokio::spawn(run_category_alert(state.clone(), alert));
+    }
+
     let app = create_router(state);
 
+    if let Some(path) = listen_uds {
+        #[cfg(unix)]
+        {
+            let listener = metarflow::server::bind_unix_socket(&path).unwrap();
+            println!("Server running on unix socket {}", path);
+            metarflow::server::serve_unix(listener, app).await.unwrap();
+            return;
+        }
+        #[cfg(not(unix))]
+        eprintln!("LISTEN_UDS ({}) needs a unix platform, listening on tcp instead", path);
+    }
+
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
         .await
         .unwrap();
     println!("Server running on http://localhost:3000");
-    axum::serve(listener, app).await.unwrap();
+    serve(listener, app).await.unwrap();
 }
+
@@ -0,0 +1,294 @@
+use std::sync::Arc;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use crate::handlers::PARSER_VERSION_HEADER;
+use crate::models::{AppState, ErrorJson};
+use crate::utils::constant_time_eq;
+
+// rejects /api requests without the configured bearer token, does nothing when API_TOKEN isn't set
+pub async fn require_api_token(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = &state.config.api_token else {
+        return next.run(request).await;
+    };
+
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(token) if constant_time_eq(token.trim().as_bytes(), expected.as_bytes()) => next.run(request).await,
+        _ => {
+            let error = ErrorJson {
+                error: "Missing or invalid API token".to_string(),
+            };
+            (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], Json(error)).into_response()
+        }
+    }
+}
+
+// a year, the usual HSTS lifetime
+const HSTS_VALUE: &str = "max-age=31536000; includeSubDomains";
+
+// the pages use inline scripts and styles and only ever call back to this origin
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; connect-src 'self'; base-uri 'self'; form-action 'self'";
+
+// with PUBLIC_HOST set, turns away requests for any other Host with a 400. with FORCE_HTTPS set, redirects
+// requests the proxy says came in over plain http to https on PUBLIC_HOST and adds HSTS. the redirect never
+// uses the request's own Host, so a forged one can't send clients elsewhere. with SECURITY_HEADERS set, adds
+// nosniff and a content security policy. does nothing when none of them is set
+pub async fn security_headers(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = &state.config;
+    if let Some(public_host) = &config.public_host {
+        // http/2 clients send the host as the :authority pseudo-header, which lands in the uri instead of Host
+        let host = request
+            .headers()
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| request.uri().authority().map(|authority| authority.as_str()))
+            .map(str::to_lowercase);
+        if host.as_deref() != Some(public_host.as_str()) {
+            let error = ErrorJson {
+                error: "Unknown host".to_string(),
+            };
+            return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+        }
+    }
+
+    if config.force_https {
+        let forwarded_proto = request
+            .headers()
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok());
+        if let (Some("http"), Some(public_host)) = (forwarded_proto, &config.public_host) {
+            let path = request.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+            return Redirect::permanent(&format!("https://{}{}", public_host, path)).into_response();
+        }
+    }
+
+    // the graphiql playground loads its scripts from a cdn, so it's left without a policy
+    let playground = request.uri().path() == "/graphql";
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    if config.force_https {
+        headers.insert(header::STRICT_TRANSPORT_SECURITY, HeaderValue::from_static(HSTS_VALUE));
+    }
+    if config.security_headers {
+        headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        if !playground {
+            headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(CONTENT_SECURITY_POLICY));
+        }
+    }
+    response
+}
+
+// the cors policy for /api/*, None when CORS_ORIGINS isn't set and browsers keep to the same origin.
+// the api is read-only, so only GET is allowed, along with the Authorization header for API_TOKEN
+pub fn api_cors_layer(origins: &[String]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET])
+            .allow_headers([header::AUTHORIZATION])
+            .expose_headers([HeaderName::from_static(PARSER_VERSION_HEADER)]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use crate::config::Config;
+    use crate::test_support;
+    use super::*;
+
+    // the app behind API_TOKEN=secret, fetching from a stand-in upstream
+    async fn locked_app() -> axum::Router {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        test_support::app(Config {
+            api_token: Some("secret".to_string()),
+            ..test_support::config_with_source(&upstream.url)
+        })
+    }
+
+    // an /api/metar request with the given Authorization header, if any
+    fn api_request(authorization: Option<&str>) -> Request<Body> {
+        let mut request = Request::get("/api/metar?icao=KJFK");
+        if let Some(value) = authorization {
+            request = request.header(header::AUTHORIZATION, value);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_the_configured_token() {
+        let app = locked_app().await;
+        let (status, _, body) = test_support::send(&app, api_request(Some("Bearer secret"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"station\":\"KJFK\""));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_token() {
+        let app = locked_app().await;
+        let (status, headers, body) = test_support::send(&app, api_request(Some("Bearer nope"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[header::WWW_AUTHENTICATE], "Bearer");
+        assert!(body.contains("Missing or invalid API token"));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_missing_header() {
+        let app = locked_app().await;
+        let (status, _, _) = test_support::send(&app, api_request(None)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_another_scheme() {
+        let app = locked_app().await;
+        let (status, _, _) = test_support::send(&app, api_request(Some("Basic secret"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn pages_stay_public() {
+        let app = locked_app().await;
+        let (status, _, _) = test_support::get(&app, "/metar?icao=KJFK").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    // the app with FORCE_HTTPS, PUBLIC_HOST and SECURITY_HEADERS set
+    async fn hardened_app() -> axum::Router {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        test_support::app(Config {
+            force_https: true,
+            public_host: Some("metar.example.com".to_string()),
+            security_headers: true,
+            ..test_support::config_with_source(&upstream.url)
+        })
+    }
+
+    fn request_with_host(uri: &str, host: &str) -> Request<Body> {
+        Request::get(uri).header(header::HOST, host).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_the_security_headers() {
+        let app = hardened_app().await;
+        let (status, headers, _) = test_support::send(&app, request_with_host("/", "metar.example.com")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::STRICT_TRANSPORT_SECURITY], HSTS_VALUE);
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::CONTENT_SECURITY_POLICY], CONTENT_SECURITY_POLICY);
+    }
+
+    #[tokio::test]
+    async fn redirects_plain_http_to_the_public_host() {
+        let app = hardened_app().await;
+        let request = Request::get("/metar?icao=KJFK")
+            .header(header::HOST, "metar.example.com")
+            .header("x-forwarded-proto", "http")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = test_support::send(&app, request).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(headers[header::LOCATION], "https://metar.example.com/metar?icao=KJFK");
+    }
+
+    #[tokio::test]
+    async fn rejects_another_host() {
+        let app = hardened_app().await;
+        let request = Request::get("/")
+            .header(header::HOST, "evil.example.net")
+            .header("x-forwarded-proto", "http")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = test_support::send(&app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(headers.get(header::LOCATION).is_none());
+
+        let (status, _, _) = test_support::send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn no_redirect_without_a_public_host() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let app = test_support::app(Config {
+            force_https: true,
+            ..test_support::config_with_source(&upstream.url)
+        });
+        let request = Request::get("/")
+            .header(header::HOST, "evil.example.net")
+            .header("x-forwarded-proto", "http")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = test_support::send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::STRICT_TRANSPORT_SECURITY], HSTS_VALUE);
+    }
+
+    // the app allowing one browser origin to call the api
+    async fn cors_app() -> axum::Router {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        test_support::app(Config {
+            cors_origins: vec!["https://dash.example.com".to_string()],
+            ..test_support::config_with_source(&upstream.url)
+        })
+    }
+
+    #[tokio::test]
+    async fn cors_allows_a_listed_origin() {
+        let app = cors_app().await;
+        let request = Request::get("/api/metar?icao=KJFK")
+            .header(header::ORIGIN, "https://dash.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = test_support::send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://dash.example.com");
+
+        let preflight = Request::options("/api/metar?icao=KJFK")
+            .header(header::ORIGIN, "https://dash.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = test_support::send(&app, preflight).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://dash.example.com");
+    }
+
+    #[tokio::test]
+    async fn cors_leaves_out_other_origins() {
+        let app = cors_app().await;
+        let request = Request::get("/api/metar?icao=KJFK")
+            .header(header::ORIGIN, "https://evil.example.net")
+            .body(Body::empty())
+            .unwrap();
+        let (_, headers, _) = test_support::send(&app, request).await;
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+}
@@ -1,31 +1,516 @@
-use serde::Deserialize;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::cache::DecodedCache;
+use crate::config::Config;
+use crate::utils::format_decimal;
 
 #[derive(Clone)]
-pub struct AppState {}
+pub struct AppState {
+    pub config: Config,
+    pub decoded: Arc<DecodedCache>,
+}
+
+impl AppState {
+    pub fn new(config: Config) -> Self {
+        AppState {
+            config,
+            decoded: Arc::new(DecodedCache::default()),
+        }
+    }
+}
+
+// settings that change how parse_metar decodes and formats a report
+#[derive(Clone)]
+pub struct DecodeOptions {
+    // decimal places for computed values, None keeps each value's usual precision
+    pub precision: Option<usize>,
+    pub derived: DerivedFields,
+    // read three-digit cloud heights as meters instead of hundreds of feet, for feeds that report them that way
+    pub cloud_heights_meters: bool,
+}
+
+// which derived values parse_metar works out, all of them unless DERIVED_FIELDS says otherwise
+#[derive(Clone)]
+pub struct DerivedFields {
+    pub flight_category: bool,
+    pub ceiling: bool,
+    pub humidity: bool,
+    pub feels_like: bool,
+    pub limiting_factor: bool,
+}
+
+impl DerivedFields {
+    // every derived field switched on
+    pub fn all() -> Self {
+        DerivedFields {
+            flight_category: true,
+            ceiling: true,
+            humidity: true,
+            feels_like: true,
+            limiting_factor: true,
+        }
+    }
+
+    // the names DERIVED_FIELDS accepts, besides "none"
+    pub const NAMES: [&'static str; 5] = ["flight_category", "ceiling", "humidity", "feels_like", "limiting_factor"];
+
+    // only the fields named in a list like "flight_category,humidity", "none" or an empty list turns them all off.
+    // names it doesn't know are left out with a warning, so a typo doesn't silently drop a field
+    pub fn from_list(value: &str) -> Self {
+        let names: Vec<String> = value
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        for name in &names {
+            if name != "none" && !Self::NAMES.contains(&name.as_str()) {
+                eprintln!("Ignoring unknown DERIVED_FIELDS entry {:?}, expected one of {}", name, Self::NAMES.join(", "));
+            }
+        }
+        let enabled = |name: &str| names.iter().any(|listed| listed == name);
+        DerivedFields {
+            flight_category: enabled("flight_category"),
+            ceiling: enabled("ceiling"),
+            humidity: enabled("humidity"),
+            feels_like: enabled("feels_like"),
+            limiting_factor: enabled("limiting_factor"),
+        }
+    }
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            precision: None,
+            derived: DerivedFields::all(),
+            cloud_heights_meters: false,
+        }
+    }
+}
+
+impl DecodeOptions {
+    // formats a computed number with the configured precision, or the value's usual one
+    pub fn format(&self, value: f64, default_places: usize) -> String {
+        format_decimal(value, self.precision.unwrap_or(default_places))
+    }
+}
+
+// a webhook to call when an airport's flight category drops below a threshold
+#[derive(Clone)]
+pub struct CategoryAlert {
+    pub icao: String,
+    // the category to stay at or above, like "MVFR"
+    pub below: String,
+    pub webhook_url: String,
+    pub poll_seconds: u64,
+}
 
-#[derive(Default)]
+// the body posted to the alert webhook
+#[derive(Serialize)]
+pub struct CategoryAlertJson<'a> {
+    pub icao: &'a str,
+    pub category: &'a str,
+    pub threshold: &'a str,
+    pub date_time: &'a str,
+    pub raw: &'a str,
+}
+
+// what the report says about one of the station's sensors
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[serde(rename_all = "snake_case")]
+pub enum SensorState {
+    Operational,
+    // installed but currently down, like "TSNO"
+    Inoperative,
+    // the station doesn't have one, like the precipitation identifier at an "AO1" station
+    NotInstalled,
+}
+
+// the state of each sensor the report mentions, None when it says nothing about it
+#[derive(Default, Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct SensorStatus {
+    pub precipitation_identifier: Option<SensorState>,
+    pub precipitation_gauge: Option<SensorState>,
+    pub freezing_rain: Option<SensorState>,
+    pub lightning: Option<SensorState>,
+    pub runway_visual_range: Option<SensorState>,
+    pub secondary_visibility: Option<SensorState>,
+    pub secondary_ceiling: Option<SensorState>,
+}
+
+impl SensorStatus {
+    // marks the sensor behind a "NO" remark like "TSNO" or "PWINO" as inoperative
+    pub fn mark_inoperative(&mut self, remark: &str) {
+        let sensor = match remark {
+            "PWINO" => &mut self.precipitation_identifier,
+            "PNO" => &mut self.precipitation_gauge,
+            "FZRANO" => &mut self.freezing_rain,
+            "TSNO" => &mut self.lightning,
+            "RVRNO" => &mut self.runway_visual_range,
+            "VISNO" => &mut self.secondary_visibility,
+            "CHINO" => &mut self.secondary_ceiling,
+            _ => return,
+        };
+        *sensor = Some(SensorState::Inoperative);
+    }
+}
+
+// a layer of icing or turbulence from a coded "6IhhhT" or "5BhhhT" remark group
+#[derive(Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct HazardLayer {
+    pub description: String,
+    pub base_feet: u32,
+    pub top_feet: u32,
+}
+
+// a runway visual range group, like "R25/0600V1200N" or "R04/P6000FT"
+#[derive(Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct RunwayVisualRange {
+    pub runway: String,
+    // the range, or the lower end of it when it varies
+    pub value: u32,
+    pub max_value: Option<u32>,
+    // "feet" when the group ends in "FT", otherwise "meters"
+    pub unit: String,
+    // "increasing", "decreasing" or "no change", from a trailing "U", "D" or "N"
+    pub tendency: Option<String>,
+    pub description: String,
+}
+
+// the cloud types from an "8/CLCMCH" remark group, None for a layer that couldn't be seen ("/")
+#[derive(Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct CloudTypes {
+    pub low: Option<String>,
+    pub middle: Option<String>,
+    pub high: Option<String>,
+}
+
+// a token parse_metar couldn't place, which phase of the report it turned up in and why
+#[derive(Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct FieldError {
+    pub token: String,
+    pub phase: String,
+    pub reason: String,
+}
+
+impl FieldError {
+    pub fn new(token: &str, phase: &str, reason: &str) -> Self {
+        FieldError {
+            token: token.to_string(),
+            phase: phase.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+// an airport offered as a shortcut in the search dropdown
+#[derive(Clone)]
+pub struct PopularAirport {
+    pub icao: String,
+    pub name: String,
+}
+
+#[derive(Default, Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
 pub struct MetarInfo {
     pub station: String,
+    // a "SPECI", issued off the usual schedule because conditions are changing
+    pub is_special: bool,
     pub date_time: String,
     pub zulu_day: Option<u32>,
     pub zulu_hour: Option<u32>,
     pub zulu_minute: Option<u32>,
     pub wind: String,
+    pub wind_dir_degrees: Option<u32>,
+    pub wind_speed_knots: Option<u32>,
+    pub wind_gust_knots: Option<u32>,
+    // set when the speed or gust was reported as a lower bound, like "P99KT"
+    pub wind_speed_above: bool,
+    pub wind_gust_above: bool,
+    // "00000KT", or calm spelled out as "CALM"
+    pub is_calm: bool,
+    pub wind_dir_from: Option<u32>,
+    pub wind_dir_to: Option<u32>,
     pub visibility: String,
+    pub visibility_miles: Option<f32>,
+    pub runway_visual_range: Vec<RunwayVisualRange>,
     pub weather: String,
     pub clouds: String,
     pub temperature: String,
     pub dewpoint: String,
+    pub temperature_c: Option<i32>,
+    pub dewpoint_c: Option<i32>,
+    // from the "T01830122" remark group, these replace the whole degrees in temperature and dewpoint when present
+    pub temperature_precise_c: Option<f32>,
+    pub dewpoint_precise_c: Option<f32>,
     pub altimeter: String,
     pub altimeter_hpa: Option<u32>,
-    pub altimeter_inches: Option<f32>,
+    pub altimeter_inhg: Option<f32>,
     pub altimeter_default_unit: String,
     pub remarks: String,
+    pub precip_period_hours: Option<u32>,
+    pub precip_period_inches: Option<f32>,
+    pub precip_24_hour_inches: Option<f32>,
+    pub precip_period_trace: bool,
+    pub precip_24_hour_trace: bool,
+    // density altitude as reported in the remarks, not computed
+    pub density_altitude_feet: Option<i32>,
+    // the wind shift, pressure rise and peak wind of a frontal passage summed up in one line, also first in the remarks
+    pub frontal_passage: Option<String>,
+    pub icing: Vec<HazardLayer>,
+    pub turbulence: Vec<HazardLayer>,
+    pub cloud_types: Option<CloudTypes>,
+    pub warnings: Vec<String>,
+    pub sensors: SensorStatus,
+    // a precipitation sensor is down ("PWINO", "PNO" or "FZRANO"), so the weather can't be taken as clear of precipitation
+    pub precipitation_unavailable: bool,
+    // the share of the core fields (wind, visibility, clouds, temperature, altimeter) that decoded, and which didn't
+    pub decode_completeness: f32,
+    pub missing_fields: Vec<String>,
+    // only in the json when asked for with field_errors=1
+    #[serde(skip)]
+    pub field_errors: Vec<FieldError>,
+    // computed from the decoded values: ceiling is the lowest broken/overcast layer, flight category is VFR, MVFR, IFR or LIFR
+    pub ceiling_feet: Option<u32>,
+    pub flight_category: Option<String>,
+    pub humidity_percent: Option<u32>,
+    pub feels_like_c: Option<i32>,
+    // the most limiting of "visibility", "ceiling", "wind" and "weather", for scanning a lot of airports at once
+    pub limiting_factor: Option<String>,
     pub raw: String,
 }
 
+// which way a value went over the recent reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrendDirection {
+    Rising,
+    Falling,
+    Steady,
+}
+
+impl TrendDirection {
+    pub fn arrow(self) -> &'static str {
+        match self {
+            TrendDirection::Rising => "↑",
+            TrendDirection::Falling => "↓",
+            TrendDirection::Steady => "→",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TrendDirection::Rising => "rising",
+            TrendDirection::Falling => "falling",
+            TrendDirection::Steady => "steady",
+        }
+    }
+}
+
+// one value over the recent reports, oldest first
+#[derive(Clone, Serialize)]
+pub struct ValueTrend {
+    pub direction: TrendDirection,
+    pub arrow: &'static str,
+    // the values as block characters, like "▁▃▅█"
+    pub sparkline: String,
+    pub values: Vec<f32>,
+}
+
+// temperature, pressure (hPa) and wind speed over the last few reports, None where fewer than two had the value
+#[derive(Clone, Serialize)]
+pub struct Trend {
+    pub observations: usize,
+    pub temperature: Option<ValueTrend>,
+    pub pressure: Option<ValueTrend>,
+    pub wind: Option<ValueTrend>,
+}
+
+// the body returned by /api/metar, the decoded fields plus the version of the decoder that produced them
+#[derive(Serialize)]
+pub struct MetarJson<'a> {
+    pub parser_version: &'static str,
+    // set when the upstream failed and this is the last report fetched for the station
+    pub stale: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field_errors: Option<&'a [FieldError]>,
+    // only with SHOW_TRENDS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trend: Option<&'a Trend>,
+    #[serde(flatten)]
+    pub metar: &'a MetarInfo,
+}
+
+// one field compared between the previous and the latest report
+#[derive(Clone, Serialize)]
+pub struct FieldChange {
+    // "wind", "visibility", "clouds" or "flight_category"
+    pub field: &'static str,
+    pub before: String,
+    pub after: String,
+    pub changed: bool,
+    // latest minus previous in the field's number: wind speed in knots, visibility in statute miles, ceiling in feet
+    // and category steps (up is better), None when either report doesn't have it
+    pub delta: Option<f32>,
+}
+
+// the body returned by /api/metar/diff, what changed between the two most recent reports
+#[derive(Serialize)]
+pub struct DiffJson<'a> {
+    pub parser_version: &'static str,
+    pub icao: &'a str,
+    pub previous_raw: &'a str,
+    pub latest_raw: &'a str,
+    // the flight category got worse
+    pub deteriorated: bool,
+    pub changes: &'a [FieldChange],
+}
+
+// the body returned by /api/metar/history, one page of the decoded reports, newest first
+#[derive(Serialize)]
+pub struct HistoryJson<'a> {
+    pub parser_version: &'static str,
+    pub icao: &'a str,
+    pub hours: u32,
+    pub page: usize,
+    pub per_page: usize,
+    // across all the pages
+    pub total: usize,
+    pub total_pages: usize,
+    pub observations: Vec<MetarInfo>,
+}
+
+// the body returned by /api/category, the flight category on its own and as one flag per category
+#[derive(Serialize)]
+pub struct CategoryJson<'a> {
+    pub icao: &'a str,
+    pub category: &'a str,
+    pub vfr: bool,
+    pub mvfr: bool,
+    pub ifr: bool,
+    pub lifr: bool,
+    // set when the upstream failed and this is the last report fetched for the station
+    pub stale: bool,
+}
+
+// the body returned by the json endpoints when the station has no current report
+#[derive(Serialize)]
+pub struct NoReportJson<'a> {
+    pub icao: &'a str,
+    pub message: String,
+}
+
+// the body returned by /api/convert
+#[derive(Serialize)]
+pub struct ConvertJson {
+    pub value: f64,
+    pub from: String,
+    pub to: String,
+    pub result: f64,
+}
+
+// the body returned by the json endpoints when something goes wrong
+#[derive(Serialize)]
+pub struct ErrorJson {
+    pub error: String,
+}
+
+// the body returned by /api/metar when the report decoded below MIN_DECODE_COMPLETENESS
+#[derive(Serialize)]
+pub struct UndecodableJson<'a> {
+    pub error: String,
+    pub raw: &'a str,
+    pub warnings: &'a [String],
+    pub decode_completeness: f32,
+    pub missing_fields: &'a [String],
+}
+
 #[derive(Deserialize)]
 pub struct MetarQuery {
     pub icao: String,
+    pub embed: Option<String>,
+    pub print: Option<String>,
+    pub field_errors: Option<String>,
+    pub source: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    pub icao: String,
+    pub embed: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub icao: String,
+    pub hours: Option<u32>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct ConvertQuery {
+    // parsed by the handler, so a value that isn't a number gets a json error like the units do
+    pub value: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize)]
+pub struct DashboardQuery {
+    pub icaos: Option<String>,
+    pub embed: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub embed: Option<String>,
+}
+
+// how a page should be laid out, from the config and the request
+pub struct PageOptions {
+    pub embed: bool,
+    pub hide_credits: bool,
+    pub print: bool,
+    // the upstream is down and the page shows the last report fetched
+    pub stale: bool,
+    // the report decoded below MIN_DECODE_COMPLETENESS
+    pub undecodable: bool,
+    // the recent trend, when SHOW_TRENDS is set and the history could be fetched
+    pub trend: Option<Trend>,
+}
+
+impl PageOptions {
+    // embed mode is on when HIDE_FOOTER is set or the request asks for it with embed=1
+    pub fn new(config: &Config, embed: Option<&str>) -> Self {
+        let embed_requested = matches!(embed, Some("1") | Some("true"));
+        PageOptions {
+            embed: config.hide_footer || embed_requested,
+            hide_credits: config.hide_credits,
+            print: false,
+            stale: false,
+            undecodable: false,
+            trend: None,
+        }
+    }
+
+    // the print view drops the interactive controls, asked for with print=1
+    pub fn with_print(mut self, print: Option<&str>) -> Self {
+        self.print = matches!(print, Some("1") | Some("true"));
+        self
+    }
+}
+
+// build details reported by the /version route
+#[derive(Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub build_time: &'static str,
+}
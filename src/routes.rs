@@ -1,15 +1,51 @@
 use axum::Router;
 use std::sync::Arc;
 use crate::handlers;
+use crate::middleware::{api_cors_layer, require_api_token, security_headers};
 use crate::models::AppState;
 
 // sets up all the routes for the web server
 pub fn create_router(state: Arc<AppState>) -> Router {
-    Router::new()
+    // the json api, which can be locked down with API_TOKEN while the html pages stay public
+    let api = Router::new()
+        .route("/api/metar", axum::routing::get(handlers::fetch_metar_json_handler))
+        .route("/api/category", axum::routing::get(handlers::category_handler))
+        .route("/api/metar/env", axum::routing::get(handlers::env_handler))
+        .route("/api/metar/history", axum::routing::get(handlers::history_handler))
+        .route("/api/metar/diff", axum::routing::get(handlers::diff_handler))
+        .route("/api/convert", axum::routing::get(handlers::convert_handler))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), require_api_token));
+    // cors sits outside the token check so preflight requests, which never carry the token, get answered
+    let api = match api_cors_layer(&state.config.cors_origins) {
+        Some(cors) => api.layer(cors),
+        None => api,
+    };
+
+    let router = Router::new()
         .route("/", axum::routing::get(handlers::index))
         .route("/metar", axum::routing::get(handlers::fetch_metar_handler))
+        .route("/dashboard", axum::routing::get(handlers::dashboard))
+        .route("/diff", axum::routing::get(handlers::diff_page))
         .route("/privacy", axum::routing::get(handlers::privacy))
+        .route("/robots.txt", axum::routing::get(handlers::robots))
         .route("/metarflow.svg", axum::routing::get(handlers::favicon))
+        .route("/version", axum::routing::get(handlers::version))
+        .merge(api);
+
+    // the graphql endpoint is api access too, so queries need the token but the playground page doesn't
+    #[cfg(feature = "graphql")]
+    let router = router
+        .route(
+            "/graphql",
+            axum::routing::get(crate::graphql::graphiql).merge(
+                axum::routing::post(crate::graphql::graphql_handler)
+                    .route_layer(axum::middleware::from_fn_with_state(state.clone(), require_api_token)),
+            ),
+        )
+        .layer(axum::Extension(crate::graphql::build_schema()));
+
+    router
+        .layer(axum::middleware::from_fn_with_state(state.clone(), security_headers))
         .with_state(state)
 }
 
@@ -0,0 +1,159 @@
+use std::time::Duration;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+// how long a client gets to send request headers before the connection is dropped
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how often idle http/2 connections are pinged, and how long to wait for the reply
+const HTTP2_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const HTTP2_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// serves the app on the listener, speaking http/1.1 or http/2 depending on what the client sends.
+// there's no tls here, http/2 is either cleartext (h2c) or negotiated by a tls-terminating proxy in front
+pub async fn serve(listener: TcpListener, app: Router) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_connection(stream, app.clone());
+    }
+}
+
+// the same as serve but on a unix domain socket, for sitting behind a proxy on the same machine
+#[cfg(unix)]
+pub async fn serve_unix(listener: UnixListener, app: Router) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_connection(stream, app.clone());
+    }
+}
+
+// binds the unix socket, first clearing out a socket file left behind by an earlier run.
+// anything at the path that isn't a socket is left alone and the bind fails
+#[cfg(unix)]
+pub fn bind_unix_socket(path: &str) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+// handles one accepted connection on its own task
+pub fn spawn_connection<S>(stream: S, app: Router)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app);
+
+    tokio::spawn(async move {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .keep_alive(true)
+            .timer(TokioTimer::new())
+            .header_read_timeout(HEADER_READ_TIMEOUT);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(HTTP2_KEEP_ALIVE_INTERVAL)
+            .keep_alive_timeout(HTTP2_KEEP_ALIVE_TIMEOUT);
+
+        if let Err(e) = builder.serve_connection(TokioIo::new(stream), service).await {
+            eprintln!("Connection error: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::config::Config;
+    use crate::models::AppState;
+    use crate::routes::create_router;
+
+    // the app on a random local port, for tests that need a real connection
+    async fn spawn_app() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = create_router(Arc::new(AppState::new(Config::default())));
+        tokio::spawn(serve(listener, app));
+        addr
+    }
+
+    #[tokio::test]
+    async fn answers_cleartext_http2() {
+        let addr = spawn_app().await;
+        let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+        let response = client.get(format!("http://{}/", addr)).send().await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert!(response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn public_host_is_checked_against_the_http2_authority() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            public_host: Some(addr.to_string()),
+            ..Config::default()
+        };
+        tokio::spawn(serve(listener, create_router(Arc::new(AppState::new(config)))));
+
+        // http/2 requests carry no Host header, only :authority
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .resolve("other.example", addr)
+            .build()
+            .unwrap();
+        let response = client.get(format!("http://{}/", addr)).send().await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert!(response.status().is_success());
+
+        let response = client.get(format!("http://other.example:{}/", addr.port())).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn still_answers_http1() {
+        let addr = spawn_app().await;
+        let response = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_11);
+        assert!(response.status().is_success());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn answers_on_a_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("metarflow-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        // a socket file left behind by an earlier run is cleared out
+        drop(bind_unix_socket(&path).unwrap());
+        let listener = bind_unix_socket(&path).unwrap();
+        let app = create_router(Arc::new(AppState::new(Config::default())));
+        tokio::spawn(serve_unix(listener, app));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /version HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains(env!("CARGO_PKG_VERSION")));
+    }
+}
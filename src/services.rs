@@ -1,30 +1,296 @@
-use crate::models::MetarInfo;
-use crate::utils::{degrees_to_cardinal, is_weather_code, decode_weather, celsius_to_fahrenheit};
+use std::sync::OnceLock;
+use crate::models::{DecodeOptions, FieldChange, FieldError, MetarInfo, SensorState, Trend, TrendDirection, ValueTrend};
+use crate::utils::{degrees_to_cardinal, is_weather_code, decode_weather, celsius_to_fahrenheit, expand_direction, precip_period_hours,
+    inhg_hundredths_to_hpa, hpa_to_inhg, not_available_label, is_temperature_group,
+    cloud_coverage_name, plain_language_remark, decode_begin_end_remark, parse_wind_speed, describe_wind_speed,
+    sensor_location, is_valid_station_code, parse_wind_variation, describe_statute_miles,
+    meters_to_statute_miles, relative_humidity, feels_like_c, flight_category, directional_visibility,
+    is_significant_weather, limiting_factor,
+    cloud_layer_height, icing_layer, turbulence_layer, cloud_types,
+    precise_temperature, runway_visual_range, sparkline, category_rank};
 
-// fetches the raw metar data from the aviation weather api for a given airport code
-pub async fn fetch_metar(icao: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!(
-        "https://aviationweather.gov/api/data/metar?ids={}&format=raw",
-        icao
-    );
+// bumped whenever the decoded output changes meaning, so api consumers can handle it deliberately
+pub const PARSER_VERSION: &str = "12";
 
-    let response = reqwest::get(&url).await?;
+// the most we'll read from the upstream, far more than any real report batch
+pub const MAX_RESPONSE_BYTES: usize = 64 * 1024;
 
+// reads a response body in chunks, giving up as soon as it grows past the limit
+pub async fn read_body_limited(mut response: reqwest::Response, limit: usize) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(length) = response.content_length() {
+        if length > limit as u64 {
+            return Err(format!("Upstream response too large ({} bytes)", length).into());
+        }
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            return Err(format!("Upstream response larger than {} bytes", limit).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+// the temperatures and dewpoints accepted from the body group, a little beyond the recorded extremes
+pub const PLAUSIBLE_TEMPERATURE_C: std::ops::RangeInclusive<i32> = -90..=60;
+
+// how many redirects the upstream may send before the fetch gives up
+pub const MAX_UPSTREAM_REDIRECTS: usize = 3;
+
+// shown for a 503 or a maintenance page, which are the upstream's problem and usually short-lived
+pub const UPSTREAM_UNAVAILABLE_MESSAGE: &str = "The upstream data service is temporarily unavailable, please try again in a few minutes";
+
+// one client for all upstream fetches, following a few redirects at most
+pub fn upstream_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::limited(MAX_UPSTREAM_REDIRECTS))
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+// why a fetch from a source= override stopped at a redirect off the override's host
+pub const OFF_HOST_REDIRECT_MESSAGE: &str = "Upstream redirected to another host";
+
+// the client for a source= override, which follows redirects only on the override's own scheme, host and port,
+// so an allowed host can't send the fetch somewhere SOURCE_OVERRIDE_HOSTS doesn't list
+pub fn override_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        let policy = reqwest::redirect::Policy::custom(|attempt| {
+            let origin = |url: &reqwest::Url| (url.scheme().to_string(), url.host_str().map(str::to_lowercase), url.port_or_known_default());
+            let same_host = attempt.previous().first().is_some_and(|first| origin(first) == origin(attempt.url()));
+            if !same_host {
+                attempt.error(OFF_HOST_REDIRECT_MESSAGE)
+            } else if attempt.previous().len() > MAX_UPSTREAM_REDIRECTS {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        });
+        reqwest::Client::builder().redirect(policy).build().unwrap_or_default()
+    })
+}
+
+// spots the maintenance or outage notice a service puts up while it's down
+pub fn looks_like_maintenance(text: &str) -> bool {
+    let text = text.to_lowercase();
+    text.contains("maintenance") || text.contains("temporarily unavailable") || text.contains("service unavailable")
+}
+
+// spots an html document, even when it's served with a plain text content type
+pub fn looks_like_html(text: &str) -> bool {
+    let start = text.trim_start().to_lowercase();
+    start.starts_with("<!doctype") || start.starts_with("<html") || start.starts_with("<?xml")
+        || start.starts_with("<head") || start.starts_with("<body")
+}
+
+// the upstream answered fine but has no current report for the station, which is normal for small airports
+#[derive(Debug)]
+pub struct NoReport(pub String);
+
+impl std::fmt::Display for NoReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No current report for {}", self.0)
+    }
+}
+
+impl std::error::Error for NoReport {}
+
+// fetches the raw metar data from the aviation weather api (or a compatible source) for a given airport code
+pub async fn fetch_metar(source_url: &str, icao: &str) -> Result<String, Box<dyn std::error::Error>> {
+    fetch_upstream(upstream_client(), source_url, icao, None).await
+}
+
+// the latest report from a source= override, through the client that keeps redirects on the override's host
+pub async fn fetch_override_metar(source_url: &str, icao: &str) -> Result<String, Box<dyn std::error::Error>> {
+    fetch_upstream(override_client(), source_url, icao, None).await
+}
+
+// fetches every report for the airport from the last few hours, newest first as the upstream lists them
+pub async fn fetch_metar_history(source_url: &str, icao: &str, hours: u32) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let text = fetch_upstream(upstream_client(), source_url, icao, Some(hours)).await?;
+    Ok(text.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect())
+}
+
+// how far back the trend on the page and in /api/metar looks
+pub const TREND_HOURS: u32 = 3;
+
+// how far a value has to move between the oldest and newest report to count as rising or falling
+pub const TEMPERATURE_TREND_STEP: f32 = 1.0;
+pub const PRESSURE_TREND_STEP: f32 = 1.0;
+pub const WIND_TREND_STEP: f32 = 5.0;
+
+// fetches the last few hours of reports and works out the trend, None when the history can't be fetched
+pub async fn fetch_trend(source_url: &str, icao: &str, options: &DecodeOptions) -> Option<Trend> {
+    let reports = fetch_metar_history(source_url, icao, TREND_HOURS).await.ok()?;
+    let decoded: Vec<MetarInfo> = reports.iter().map(|raw| parse_metar(raw, icao, options)).collect();
+    compute_trend(&decoded)
+}
+
+// the trend over reports given newest first, as the upstream lists them. None with fewer than two reports
+pub fn compute_trend(reports: &[MetarInfo]) -> Option<Trend> {
+    if reports.len() < 2 {
+        return None;
+    }
+    let series = |value: fn(&MetarInfo) -> Option<f32>, step: f32| {
+        let values: Vec<f32> = reports.iter().rev().filter_map(value).collect();
+        value_trend(values, step)
+    };
+    Some(Trend {
+        observations: reports.len(),
+        temperature: series(|info| info.temperature_precise_c.or(info.temperature_c.map(|c| c as f32)), TEMPERATURE_TREND_STEP),
+        pressure: series(|info| info.altimeter_hpa.map(|hpa| hpa as f32), PRESSURE_TREND_STEP),
+        wind: series(|info| info.wind_speed_knots.map(|knots| knots as f32), WIND_TREND_STEP),
+    })
+}
+
+// which way the values (oldest first) went, comparing the newest with the oldest
+pub fn value_trend(values: Vec<f32>, step: f32) -> Option<ValueTrend> {
+    if values.len() < 2 {
+        return None;
+    }
+    let change = values[values.len() - 1] - values[0];
+    let direction = if change >= step {
+        TrendDirection::Rising
+    } else if change <= -step {
+        TrendDirection::Falling
+    } else {
+        TrendDirection::Steady
+    };
+    Some(ValueTrend {
+        direction,
+        arrow: direction.arrow(),
+        sparkline: sparkline(&values),
+        values,
+    })
+}
+
+// compares the fields that say the most about conditions between two reports of the same airport
+pub fn diff_reports(previous: &MetarInfo, latest: &MetarInfo) -> Vec<FieldChange> {
+    let change = |field: &'static str, before: &str, after: &str, numbers: (Option<f32>, Option<f32>)| FieldChange {
+        field,
+        before: before.to_string(),
+        after: after.to_string(),
+        changed: before != after,
+        delta: match numbers {
+            (Some(before), Some(after)) => Some(((after - before) * 100.0).round() / 100.0),
+            _ => None,
+        },
+    };
+    let knots = |info: &MetarInfo| info.wind_speed_knots.map(|knots| knots as f32);
+    let ceiling = |info: &MetarInfo| info.ceiling_feet.map(|feet| feet as f32);
+    let rank = |info: &MetarInfo| info.flight_category.as_deref().and_then(category_rank).map(f32::from);
+
+    vec![
+        change("wind", &previous.wind, &latest.wind, (knots(previous), knots(latest))),
+        change("visibility", &previous.visibility, &latest.visibility, (previous.visibility_miles, latest.visibility_miles)),
+        change("clouds", &previous.clouds, &latest.clouds, (ceiling(previous), ceiling(latest))),
+        change(
+            "flight_category",
+            previous.flight_category.as_deref().unwrap_or_default(),
+            latest.flight_category.as_deref().unwrap_or_default(),
+            (rank(previous), rank(latest)),
+        ),
+    ]
+}
+
+// the raw text the upstream has for the airport, the latest report or with hours set all of them from that far back
+pub async fn fetch_upstream(client: &reqwest::Client, source_url: &str, icao: &str, hours: Option<u32>) -> Result<String, Box<dyn std::error::Error>> {
+    // the code ends up in the upstream url, so refuse anything that isn't a plain station code
+    if !is_valid_station_code(icao) {
+        return Err(format!("Invalid airport code {:?}", icao).into());
+    }
+    let mut params = vec![("ids", icao.to_string()), ("format", "raw".to_string())];
+    if let Some(hours) = hours {
+        params.push(("hours", hours.to_string()));
+    }
+    let url = reqwest::Url::parse_with_params(source_url, &params)?;
+
+    let response = client.get(url).send().await.map_err(|e| -> Box<dyn std::error::Error> {
+        let off_host = std::error::Error::source(&e).is_some_and(|reason| reason.to_string() == OFF_HOST_REDIRECT_MESSAGE);
+        if off_host {
+            OFF_HOST_REDIRECT_MESSAGE.into()
+        } else if e.is_redirect() {
+            format!("Upstream redirected more than {} times", MAX_UPSTREAM_REDIRECTS).into()
+        } else {
+            e.into()
+        }
+    })?;
+
+    if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+        return Err(UPSTREAM_UNAVAILABLE_MESSAGE.into());
+    }
     if !response.status().is_success() {
         return Err(format!("Failed to fetch data: {}", response.status()).into());
     }
 
-    let text = response.text().await?;
+    // an error page or bot challenge from a proxy would otherwise get parsed as a garbage metar
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    if content_type.contains("json") {
+        return Err(format!("Upstream returned an unexpected format ({})", content_type).into());
+    }
+
+    let text = read_body_limited(response, MAX_RESPONSE_BYTES).await?;
+
+    // a redirect can land on a maintenance notice served with a 200
+    if content_type.contains("html") || looks_like_html(&text) {
+        if looks_like_maintenance(&text) {
+            return Err(UPSTREAM_UNAVAILABLE_MESSAGE.into());
+        }
+        return Err("Upstream returned an unexpected format (HTML page)".into());
+    }
 
     if text.trim().is_empty() {
-        return Err(format!("No METAR data found for airport {}", icao).into());
+        return Err(Box::new(NoReport(icao.to_string())));
     }
 
     Ok(text.trim().to_string())
 }
 
+// fetches and parses a known-good airport once, to catch a broken upstream or source url before serving traffic
+pub async fn check_upstream(source_url: &str, options: &DecodeOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let icao = "KJFK";
+    let metar = fetch_metar(source_url, icao).await?;
+    let info = parse_metar(&metar, icao, options);
+
+    if info.date_time.is_empty() {
+        return Err(format!("Upstream returned a report that couldn't be parsed: {}", metar).into());
+    }
+
+    Ok(())
+}
+
+// uppercases a report and splits it into tokens, joining a "KT" that got separated from its wind group
+pub fn normalize_tokens(metar: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for token in metar.split_whitespace() {
+        let token = token.to_uppercase();
+        if token == "KT" {
+            if let Some(previous) = tokens.last_mut() {
+                if previous.ends_with(|c: char| c.is_ascii_digit() || c == '/') {
+                    previous.push_str("KT");
+                    continue;
+                }
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
 // parses a raw metar string and extracts all the weather information into a structured format
-pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
+pub fn parse_metar(metar: &str, icao: &str, options: &DecodeOptions) -> MetarInfo {
     // start with a default metar info struct, setting the station code and raw string
     let mut info = MetarInfo {
         station: icao.to_string(),
@@ -32,8 +298,10 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
         ..Default::default()
     };
 
-    // split the metar string into individual parts by whitespace
-    let parts: Vec<&str> = metar.split_whitespace().collect();
+    // split the metar string into individual parts by whitespace. some feeds send lowercase
+    // or split the units off the wind ("27015 KT"), so tidy that up first, the raw text stays as sent
+    let tokens = normalize_tokens(metar);
+    let parts: Vec<&str> = tokens.iter().map(String::as_str).collect();
     if parts.is_empty() {
         return info;
     }
@@ -43,6 +311,7 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
 
     // skip the "METAR" or "SPECI" prefix if it's there
     if i < parts.len() && (parts[i] == "METAR" || parts[i] == "SPECI") {
+        info.is_special = parts[i] == "SPECI";
         i += 1;
     }
 
@@ -57,9 +326,9 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
         let dt = parts[i];
         // parse out the day (first 2 digits), hour (next 2), and minute (next 2)
         if let (Ok(day), Ok(hour), Ok(min)) = (
-            dt[0..2].parse::<u32>(),
-            dt[2..4].parse::<u32>(),
-            dt[4..6].parse::<u32>(),
+            dt.get(0..2).unwrap_or_default().parse::<u32>(),
+            dt.get(2..4).unwrap_or_default().parse::<u32>(),
+            dt.get(4..6).unwrap_or_default().parse::<u32>(),
         ) {
             info.zulu_day = Some(day);
             info.zulu_hour = Some(hour);
@@ -74,68 +343,104 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
         i += 1;
     }
 
-    // parse the wind information
+    // the wind phase is "[dddVddd] wind [dddVddd]": the variable direction range normally follows the
+    // wind group, but some feeds put it first. only a strict 3-digit "V" 3-digit token counts, so
+    // visibility and runway groups can't be mistaken for it
+    let mut wind_variation = parts.get(i).and_then(|part| parse_wind_variation(part));
+    if wind_variation.is_some() {
+        i += 1;
+    }
+
+    // parse the wind information. automated remote stations sometimes leave the group out, so each branch only
+    // moves past the token when it matches and anything else is left for the visibility and weather phases
     if i < parts.len() {
         let wind = parts[i];
-        // check if it's variable wind
-        if wind.starts_with("VRB") {
-            // extract the wind speed
-            if let Ok(speed) = wind[3..5].parse::<u32>() {
-                info.wind = format!("Variable at {} knots", speed);
+        // some feeds spell calm out instead of sending "00000KT"
+        if wind == "CALM" || wind == "CLM" {
+            info.wind = "Calm".to_string();
+            info.wind_speed_knots = Some(0);
+            info.is_calm = true;
+            i += 1;
+        } else if wind.starts_with("VRB") {
+            // variable wind
+            // extract the wind speed and any gust, like "VRB05KT" or "VRB05G15KT"
+            let body = wind.trim_end_matches("KT");
+            let speed_end = body.find('G').unwrap_or(body.len());
+            if let Some((speed, above)) = body.get(3..speed_end).and_then(parse_wind_speed) {
+                info.wind = format!("Variable at {}", describe_wind_speed(speed, above));
+                info.wind_speed_knots = Some(speed);
+                info.wind_speed_above = above;
                 // check for gusts
-                if wind.contains('G') {
-                    if let Some(gust_pos) = wind.find('G') {
-                        if let Ok(gust) = wind[gust_pos + 1..gust_pos + 3].parse::<u32>() {
-                            info.wind = format!("Variable at {} knots, gusting to {} knots", speed, gust);
-                        }
-                    }
+                if let Some((gust, gust_above)) = body.get(speed_end + 1..).and_then(parse_wind_speed) {
+                    info.wind.push_str(&format!(", gusting to {}", describe_wind_speed(gust, gust_above)));
+                    info.wind_gust_knots = Some(gust);
+                    info.wind_gust_above = gust_above;
                 }
                 i += 1;
             }
+        } else if wind.len() >= 6 && wind.ends_with("KT") && wind.contains('/') {
+            // partial sensor data, like "270//KT" (speed unknown) or "/////KT" (nothing measured)
+            let dir = wind.get(0..3).and_then(|d| d.parse::<u32>().ok());
+            let speed = wind.get(3..5).and_then(|s| s.parse::<u32>().ok());
+            info.wind_dir_degrees = dir;
+            info.wind_speed_knots = speed;
+            info.wind = match (dir, speed) {
+                (Some(dir), None) => format!("{} degrees ({}), wind speed not measured", dir, degrees_to_cardinal(dir)),
+                (None, Some(speed)) => format!("Wind direction not measured, {} knots", speed),
+                _ => "Wind not measured".to_string(),
+            };
+            i += 1;
         } else if wind.len() >= 7 && wind.ends_with("KT") {
             // normal wind format: direction (3 digits) + speed (2 digits) + "KT"
-            // like "27015KT" means 270 degrees at 15 knots
-            if let Ok(dir) = wind[0..3].parse::<u32>() {
-                if let Ok(speed) = wind[3..5].parse::<u32>() {
+            // like "27015KT" means 270 degrees at 15 knots, "240P99KT" means more than 99 knots
+            let body = &wind[..wind.len() - 2];
+            let speed_end = body.find('G').unwrap_or(body.len());
+            if let Ok(dir) = wind.get(0..3).unwrap_or_default().parse::<u32>() {
+                if let Some((speed, above)) = body.get(3..speed_end).and_then(parse_wind_speed) {
                     let dir_cardinal = degrees_to_cardinal(dir);
-                    // check for gusts
-                    if wind.contains('G') {
-                        if let Some(gust_pos) = wind.find('G') {
-                            if let Ok(gust) = wind[gust_pos + 1..gust_pos + 3].parse::<u32>() {
-                                info.wind = format!("{} degrees ({}) at {} knots, gusting to {} knots", dir, dir_cardinal, speed, gust);
-                            } else {
-                                info.wind = format!("{} degrees ({}) at {} knots", dir, dir_cardinal, speed);
-                            }
-                        }
+                    if dir == 0 && speed == 0 && speed_end == body.len() {
+                        info.wind = "Calm".to_string();
+                        info.is_calm = true;
                     } else {
-                        info.wind = format!("{} degrees ({}) at {} knots", dir, dir_cardinal, speed);
+                        info.wind = format!("{} degrees ({}) at {}", dir, dir_cardinal, describe_wind_speed(speed, above));
                     }
-                    i += 1;
-                    
-                    // check for variable wind direction (like "200V250" meaning wind varies between 200 and 250 degrees)
-                    if i < parts.len() {
-                        let var_wind = parts[i];
-                        if var_wind.contains('V') && var_wind.len() >= 5 {
-                            if let Some(v_pos) = var_wind.find('V') {
-                                if let (Ok(from_dir), Ok(to_dir)) = (
-                                    var_wind[0..v_pos].parse::<u32>(),
-                                    var_wind[v_pos + 1..].parse::<u32>(),
-                                ) {
-                                    info.wind.push_str(&format!(", variable between {} and {} degrees", from_dir, to_dir));
-                                    i += 1;
-                                }
-                            }
-                        }
+                    info.wind_dir_degrees = Some(dir);
+                    info.wind_speed_knots = Some(speed);
+                    info.wind_speed_above = above;
+                    // check for gusts
+                    if let Some((gust, gust_above)) = body.get(speed_end + 1..).and_then(parse_wind_speed) {
+                        info.wind.push_str(&format!(", gusting to {}", describe_wind_speed(gust, gust_above)));
+                        info.wind_gust_knots = Some(gust);
+                        info.wind_gust_above = gust_above;
                     }
+                    i += 1;
                 }
             }
         }
     }
 
+    // check for a variable wind direction after the wind group (like "200V250" meaning wind varies between 200 and 250 degrees)
+    if wind_variation.is_none() && !info.wind.is_empty() {
+        wind_variation = parts.get(i).and_then(|part| parse_wind_variation(part));
+        if wind_variation.is_some() {
+            i += 1;
+        }
+    }
+    if let Some((from_dir, to_dir)) = wind_variation {
+        if !info.wind.is_empty() {
+            // "VRB03KT 180V240" already reads "Variable at 3 knots", so the range doesn't say variable twice
+            let word = if info.wind.starts_with("Variable") { "varying" } else { "variable" };
+            info.wind.push_str(&format!(", {} between {} and {} degrees", word, from_dir, to_dir));
+        }
+        info.wind_dir_from = Some(from_dir);
+        info.wind_dir_to = Some(to_dir);
+    }
+
     // check for CAVOK (ceiling and visibility okay) this means perfect conditions
     let mut cavok_found = false;
     if i < parts.len() && parts[i] == "CAVOK" {
         info.visibility = "10 kilometers or more".to_string();
+        info.visibility_miles = Some(meters_to_statute_miles(10_000));
         info.clouds = "No clouds below 5,000 feet".to_string();
         info.weather = "None significant".to_string();
         cavok_found = true;
@@ -144,18 +449,42 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
 
     // parse visibility if we didn't find CAVOK
     if !cavok_found && i < parts.len() {
-        let vis = parts[i];
+        // automated stations may add "NDV" (no directional variation) to a meters value, like "1200NDV"
+        let (vis, ndv) = match parts[i].strip_suffix("NDV") {
+            Some(meters) => (meters, true),
+            None => (parts[i], false),
+        };
+        let mut metric = false;
         // "9999" means 10km or more, "SM" means statute miles
-        if vis == "9999" || vis.ends_with("SM") {
+        if is_temperature_group(vis) {
+            // an "M" here would be a minus sign, so a temperature group in the wrong place is left for the temperature phase
+        } else if vis == "////" || vis == "////SM" {
+            // the sensor is out or the observer couldn't tell, there's no value to decode
+            info.visibility = "Visibility not measured".to_string();
+            i += 1;
+        } else if vis == "9999" || vis.ends_with("SM") {
             if vis == "9999" {
                 info.visibility = "10 kilometers or more".to_string();
-            } else if let Ok(miles) = vis[0..vis.len() - 2].parse::<f32>() {
-                info.visibility = format!("{} statute miles", miles);
+                info.visibility_miles = Some(meters_to_statute_miles(10_000));
+                metric = true;
+            } else if let Some((text, miles)) = vis.strip_suffix("SM").and_then(describe_statute_miles) {
+                info.visibility = text;
+                info.visibility_miles = Some(miles);
             }
             i += 1;
+        } else if !ndv && vis.len() == 1 && parts.get(i + 1).is_some_and(|next| next.ends_with("SM")) {
+            // whole miles and a fraction split over two tokens, like "1 1/2SM"
+            let combined = format!("{} {}", vis, parts[i + 1].trim_end_matches("SM"));
+            if let Some((text, miles)) = describe_statute_miles(&combined) {
+                info.visibility = text;
+                info.visibility_miles = Some(miles);
+                i += 2;
+            }
         } else if vis.parse::<u32>().is_ok() {
             // visibility in meters
             if let Ok(meters) = vis.parse::<u32>() {
+                info.visibility_miles = Some(meters_to_statute_miles(meters));
+                metric = true;
                 if meters >= 1000 {
                     info.visibility = format!("{} kilometers", meters / 1000);
                 } else {
@@ -164,9 +493,32 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
             }
             i += 1;
         }
+        if ndv && !info.visibility.is_empty() {
+            info.visibility.push_str(", no directional variation");
+        }
+        // a metric visibility can be followed by the minimum in one direction, like "9999 2000SW"
+        if metric {
+            if let Some((meters, direction)) = parts.get(i).and_then(|part| directional_visibility(part)) {
+                info.visibility.push_str(&format!(", down to {} meters to the {}", meters, direction));
+                i += 1;
+            }
+        }
+    }
+
+    // runway visual range groups follow the visibility, like "R25/0600V1200N"
+    if !cavok_found {
+        while let Some(rvr) = parts.get(i).and_then(|part| runway_visual_range(part)) {
+            if !info.visibility.is_empty() {
+                info.visibility.push_str("; ");
+            }
+            info.visibility.push_str(&rvr.description);
+            info.runway_visual_range.push(rvr);
+            i += 1;
+        }
     }
 
     // parse weather conditions
+    let mut significant_weather = false;
     if !cavok_found {
         while i < parts.len() {
             let part = parts[i];
@@ -183,6 +535,7 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
                 || is_weather_code(part);
 
             if is_weather {
+                significant_weather |= is_significant_weather(part);
                 // decode the weather code to readable text
                 let weather_desc = decode_weather(part);
                 if !weather_desc.is_empty() {
@@ -202,9 +555,12 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
         }
     }
 
-    // parse cloud information
+    // parse cloud information. the ceiling is the lowest broken, overcast or vertical visibility layer,
+    // and with no such layer reported it's unlimited
+    let mut sky_reported = cavok_found;
     if !cavok_found {
         let mut cloud_layers = Vec::new();
+        let mut ceiling_known = true;
         while i < parts.len() {
             let part = parts[i];
             // sky clear
@@ -227,31 +583,27 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
                 cloud_layers.push("No cloud detected".to_string());
                 i += 1;
                 break;
-            } else if part.starts_with("VV") {
+            } else if let Some(height) = part.strip_prefix("VV") {
                 // vertical visibility (sky obscured)
-                if part.len() >= 5 {
-                    if let Ok(alt) = part[2..5].parse::<u32>() {
-                        let altitude = alt * 100; // altitude is in hundreds of feet
-                        cloud_layers.push(format!("Sky obscured, vertical visibility {} feet", altitude));
+                if height.len() >= 3 {
+                    if let Some((altitude, meters)) = cloud_layer_height(&layer_digits(height), options.cloud_heights_meters) {
+                        cloud_layers.push(format!("Sky obscured, vertical visibility {} feet{}", altitude, meters_note(meters)));
+                        info.ceiling_feet = Some(info.ceiling_feet.map_or(altitude, |lowest| lowest.min(altitude)));
+                    } else {
+                        ceiling_known = false;
                     }
                 } else {
                     cloud_layers.push("Sky obscured".to_string());
+                    ceiling_known = false;
                 }
                 i += 1;
             } else if part.starts_with("FEW") || part.starts_with("SCT") 
                 || part.starts_with("BKN") || part.starts_with("OVC") {
                 // cloud coverage codes: FEW (few), SCT (scattered), BKN (broken), OVC (overcast)
-                let coverage = match &part[0..3] {
-                    "FEW" => "Few",
-                    "SCT" => "Scattered",
-                    "BKN" => "Broken",
-                    "OVC" => "Overcast",
-                    _ => "",
-                };
+                let coverage = cloud_coverage_name(&part[0..3]).unwrap_or("");
                 if !coverage.is_empty() && part.len() >= 6 {
-                    // extract altitude (in hundreds of feet)
-                    if let Ok(alt) = part[3..6].parse::<u32>() {
-                        let altitude = alt * 100;
+                    // extract altitude (in hundreds of feet, or meters, see cloud_layer_height)
+                    if let Some((altitude, meters)) = cloud_layer_height(&layer_digits(&part[3..]), options.cloud_heights_meters) {
                         // check for special cloud types
                         let cloud_type = if part.ends_with("CB") {
                             " (cumulonimbus)"
@@ -260,16 +612,20 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
                         } else {
                             ""
                         };
-                        cloud_layers.push(format!("{} at {} feet{}", coverage, altitude, cloud_type));
+                        cloud_layers.push(format!("{} at {} feet{}{}", coverage, altitude, meters_note(meters), cloud_type));
+                        if part.starts_with("BKN") || part.starts_with("OVC") {
+                            info.ceiling_feet = Some(info.ceiling_feet.map_or(altitude, |lowest| lowest.min(altitude)));
+                        }
                     }
                 }
                 i += 1;
             } else if part.starts_with("A") || part.starts_with("Q") || part.starts_with("T") 
-                || part.starts_with("M") || part.starts_with("RMK") || part.starts_with("NOSIG") {
-                break;
-            } else if part.contains('/') && part.len() <= 7 {
+                || part.starts_with("M") || part.starts_with("RMK") || part.starts_with("NOSIG")
+                || is_temperature_group(part) {
+                // the temperature group can turn up early in odd encodings, stop here so the temperature phase gets it
                 break;
             } else {
+                info.field_errors.push(FieldError::new(part, "clouds", "Not a weather or cloud group"));
                 i += 1;
             }
         }
@@ -278,6 +634,7 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
             info.clouds = "No cloud information".to_string();
         } else {
             info.clouds = cloud_layers.join(", ");
+            sky_reported = ceiling_known;
         }
     }
 
@@ -285,8 +642,9 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
     while i < parts.len() {
         let part = parts[i];
 
-        // temperature and dewpoint are in format like "15/10" or "M05/M10" (M means negative)
-        if part.contains('/') && part.len() <= 7 {
+        // temperature and dewpoint are in format like "15/10" or "M05/M10" (M means negative).
+        // only a strict group counts, so a visibility like "M1/4SM" can't be read as minus one degree
+        if is_temperature_group(part) {
             let temp_parts: Vec<&str> = part.split('/').collect();
             if temp_parts.len() == 2 {
                 let mut temp_str = temp_parts[0];
@@ -295,9 +653,6 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
                 if temp_str.starts_with('M') {
                     temp_neg = true;
                     temp_str = &temp_str[1..];
-                } else if temp_str.starts_with('T') {
-                    // T means trace, just skip it
-                    temp_str = &temp_str[1..];
                 }
 
                 let mut dew_str = temp_parts[1];
@@ -311,12 +666,20 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
                 // parse both temperatures
                 if let (Ok(temp_c), Ok(dew_c)) = (temp_str.parse::<i32>(), dew_str.parse::<i32>()) {
                     let temp_c = if temp_neg { -temp_c } else { temp_c };
+                    let dew_c = if dew_neg { -dew_c } else { dew_c };
+                    // a value no station could report is a garbled group, better left out than shown
+                    if !PLAUSIBLE_TEMPERATURE_C.contains(&temp_c) || !PLAUSIBLE_TEMPERATURE_C.contains(&dew_c) {
+                        info.warnings.push(format!("Temperature group {} is out of range and was ignored", part));
+                        i += 1;
+                        continue;
+                    }
                     let temp_f = celsius_to_fahrenheit(temp_c);
                     info.temperature = format!("{}°C ({}°F)", temp_c, temp_f);
+                    info.temperature_c = Some(temp_c);
 
-                    let dew_c = if dew_neg { -dew_c } else { dew_c };
                     let dew_f = celsius_to_fahrenheit(dew_c);
                     info.dewpoint = format!("{}°C ({}°F)", dew_c, dew_f);
+                    info.dewpoint_c = Some(dew_c);
 
                     i += 1;
                     continue;
@@ -326,65 +689,256 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
 
         // altimeter setting "A" prefix means inches of mercury
         if part.starts_with("A") && part.len() == 5 {
-            if let Ok(inches_raw) = part[1..].parse::<f32>() {
-                let inches = inches_raw / 100.0; // convert from hundredths
-                let inches_str = format!("{:.2}", inches);
-                let hpa = (inches * 33.8639) as u32; // convert to hectopascals
+            if let Ok(hundredths) = part[1..].parse::<u32>() {
+                // work from the whole hundredths so the conversions don't pick up float error
+                let inches_str = format!("{}.{:02}", hundredths / 100, hundredths % 100);
+                let hpa = inhg_hundredths_to_hpa(hundredths); // convert to hectopascals
                 info.altimeter = format!("{} inches of mercury", inches_str);
-                info.altimeter_inches = Some(inches);
+                info.altimeter_inhg = Some(hundredths as f32 / 100.0);
                 info.altimeter_hpa = Some(hpa);
                 info.altimeter_default_unit = "inches".to_string();
+            } else {
+                info.field_errors.push(FieldError::new(part, "altimeter", "Altimeter setting isn't a number"));
             }
             i += 1;
         } else if part.starts_with("Q") && part.len() == 5 {
             // "Q" prefix means hectopascals
             if let Ok(hpa) = part[1..].parse::<u32>() {
-                let inches = hpa as f32 / 33.8639; // convert to inches
+                let inches = hpa_to_inhg(hpa); // convert to inches
                 info.altimeter = format!("{} hectopascals", hpa);
                 info.altimeter_hpa = Some(hpa);
-                info.altimeter_inches = Some(inches);
+                info.altimeter_inhg = Some(inches);
                 info.altimeter_default_unit = "hpa".to_string();
+            } else {
+                info.field_errors.push(FieldError::new(part, "altimeter", "Altimeter setting isn't a number"));
             }
             i += 1;
         } else if part.starts_with("RMK") {
             // remarks section - contains additional information
             i += 1;
             let mut remark_parts = Vec::new();
+            // tokens we couldn't decode, kept verbatim so nothing is silently dropped
+            let mut undecoded: Vec<&str> = Vec::new();
+            // what the remarks say about a front going through, put together once they're all read
+            let mut wind_shift_time = None;
+            let mut frontal_passage = false;
+            let mut pressure_rising = false;
+            let mut peak_wind = None;
             // parse remarks until we hit "$" or end of string
             while i < parts.len() && parts[i] != "$" {
                 let remark = parts[i];
+                let decoded_before = remark_parts.len();
+                // set by groups that refine the body fields instead of adding a remark
+                let mut applied = false;
                 if remark.starts_with("AO") {
                     remark_parts.push("Automated station".to_string());
-                } else if remark.starts_with("RAE") {
-                    // rain ended at X minutes past the hour
-                    if let Ok(min) = remark[3..].parse::<u32>() {
-                        remark_parts.push(format!("Rain ended at {} minutes past the hour", min));
+                    // "AO2" stations can tell rain from snow, "AO1" ones have no precipitation identifier
+                    if remark.starts_with("AO1") {
+                        info.sensors.precipitation_identifier = Some(SensorState::NotInstalled);
+                    } else if remark.starts_with("AO2") {
+                        info.sensors.precipitation_identifier = Some(SensorState::Operational);
+                    }
+                } else if remark == "FIRST" {
+                    // first observation after the station opens
+                    remark_parts.push("First observation of the period".to_string());
+                } else if remark == "LAST" {
+                    // last observation before the station closes
+                    remark_parts.push("Last observation of the period".to_string());
+                } else if let Some(label) = not_available_label(remark) {
+                    // any known group with a "NO" suffix means that value isn't available right now
+                    let mut message = format!("{} not available", label);
+                    info.sensors.mark_inoperative(remark);
+                    // secondary sensors name where they are, like "CHINO RWY24"
+                    if remark == "VISNO" || remark == "CHINO" {
+                        if let Some(location) = parts.get(i + 1).and_then(|next| sensor_location(next)) {
+                            message = format!("{} ({}) not available", label, location);
+                            i += 1;
+                        }
+                    }
+                    info.warnings.push(message.clone());
+                    remark_parts.push(message);
+                } else if let Some((desc, used)) = decode_snow_increase_remark(&parts[i..]) {
+                    // snow increasing rapidly, like "SNINCR 2/10"
+                    remark_parts.push(desc);
+                    i += used - 1;
+                } else if let Some((dir, speed, time, used)) = peak_wind_remark(&parts[i..]) {
+                    // peak wind since the last report, like "PK WND 28045/1530"
+                    remark_parts.push(format!("Peak wind {}° at {} knots at {}", dir, speed, time));
+                    peak_wind = Some(format!("{}° at {} kt", dir, speed));
+                    i += used - 1;
+                } else if let Some((text, used)) = plain_language_remark(&parts[i..]) {
+                    pressure_rising |= remark == "PRESRR";
+                    remark_parts.push(text.to_string());
+                    i += used - 1;
+                } else if let Some((feet, used)) = density_altitude_remark(&parts[i..]) {
+                    // reported density altitude, like "DENSITY ALT 1200FT"
+                    info.density_altitude_feet = Some(feet);
+                    remark_parts.push(format!("Density altitude {} feet", feet));
+                    i += used - 1;
+                } else if let Some((desc, used)) = decode_ceiling_remark(&parts[i..]) {
+                    // ceiling height, like "CIG 015", "CIG 005V010" or "CIG 002 RWY11"
+                    remark_parts.push(desc);
+                    i += used - 1;
+                } else if remark == "WSHFT" {
+                    // wind shift, like "WSHFT 1530 FROPA"
+                    let (desc, used) = decode_wind_shift_remark(&parts[i..]);
+                    wind_shift_time = parts.get(i + 1).and_then(|time| remark_time(time));
+                    frontal_passage = parts[i..i + used].contains(&"FROPA");
+                    remark_parts.push(desc);
+                    i += used - 1;
+                } else if i + 2 < parts.len() && parts[i + 1] == "V" && remark.len() == 6
+                    && cloud_coverage_name(remark.get(0..3).unwrap_or_default()).is_some()
+                    && remark[3..].chars().all(|c| c.is_ascii_digit())
+                    && cloud_coverage_name(parts[i + 2]).is_some() {
+                    // a layer whose coverage varies, like "SCT015 V BKN"
+                    let from = cloud_coverage_name(&remark[0..3]).unwrap_or_default();
+                    let to = cloud_coverage_name(parts[i + 2]).unwrap_or_default().to_lowercase();
+                    let alt = remark[3..].parse::<u32>().unwrap_or_default() * 100;
+                    remark_parts.push(format!("{} variable {} at {} feet", from, to, alt));
+                    i += 2;
+                } else if remark == "VIRGA" {
+                    // precipitation not reaching the ground, optionally located like "VIRGA SW"
+                    match phenomenon_location(&parts[i + 1..]) {
+                        Some((location, used)) => {
+                            remark_parts.push(format!("Virga {}", location));
+                            i += used;
+                        }
+                        None => remark_parts.push("Virga (precipitation not reaching the ground)".to_string()),
+                    }
+                } else if remark == "SH" {
+                    // showers seen away from the station, like "SH VC" or "SH DSNT NE"
+                    match phenomenon_location(&parts[i + 1..]) {
+                        Some((location, used)) => {
+                            remark_parts.push(format!("Showers {}", location));
+                            i += used;
+                        }
+                        None => remark_parts.push("Showers".to_string()),
                     }
+                } else if remark == "TS" {
+                    // thunderstorm location and movement, like "TS SE MOV NE"
+                    let (desc, used) = decode_thunderstorm_remark(&parts[i..]);
+                    remark_parts.push(desc);
+                    i += used - 1;
+                } else if remark.starts_with("LTG")
+                    || ((remark == "FRQ" || remark == "OCNL" || remark == "CONS")
+                        && i + 1 < parts.len() && parts[i + 1].starts_with("LTG")) {
+                    // lightning with an optional frequency and location, like "FRQ LTG NW"
+                    let (desc, used) = decode_lightning_remark(&parts[i..]);
+                    remark_parts.push(desc);
+                    i += used - 1;
+                } else if let Some(desc) = decode_begin_end_remark(remark) {
+                    // weather began/ended times, like "RAB05E20" or "SNB1215"
+                    remark_parts.push(desc);
                 } else if remark.starts_with("P") && remark.len() > 1 {
                     // precipitation amount
                     if let Ok(precip) = remark[1..].parse::<f32>() {
                         if precip == 0.0 {
                             remark_parts.push("No precipitation in past hour".to_string());
                         } else {
-                            remark_parts.push(format!("Precipitation: {} inches", precip / 100.0));
+                            remark_parts.push(format!("Precipitation: {} inches", options.format(precip as f64 / 100.0, 2)));
                         }
                     }
-                } else if remark.starts_with("T") && remark.len() > 1 && remark.contains('/') {
-                    // precise temperature/dewpoint (in tenths of degrees)
-                    let temp_parts: Vec<&str> = remark[1..].split('/').collect();
-                    if temp_parts.len() == 2 {
-                        if let (Ok(temp_int), Ok(dew_int)) = (temp_parts[0].parse::<i32>(), temp_parts[1].parse::<i32>()) {
-                            let temp_c = temp_int as f32 / 10.0;
-                            let dew_c = dew_int as f32 / 10.0;
-                            remark_parts.push(format!("Precise temperature: {:.1}°C / {:.1}°C", temp_c, dew_c));
+                } else if remark.len() == 5 && (remark.starts_with('6') || remark.starts_with('7'))
+                    && (&remark[1..] == "////" || remark[1..].chars().all(|c| c.is_ascii_digit())) {
+                    // "6RRRR" is 3 or 6 hour precipitation and "7RRRR" is 24 hour, in hundredths of an inch.
+                    // all zeros means a trace (too little to measure, so no amount is set) and "////" means
+                    // the amount couldn't be measured
+                    let period = if remark.starts_with('7') {
+                        "24-hour".to_string()
+                    } else {
+                        match (info.zulu_hour, info.zulu_minute) {
+                            (Some(hour), Some(min)) => match precip_period_hours(hour, min) {
+                                Some(hours) => {
+                                    info.precip_period_hours = Some(hours);
+                                    format!("{}-hour", hours)
+                                }
+                                None => "3/6-hour".to_string(),
+                            },
+                            _ => "3/6-hour".to_string(),
+                        }
+                    };
+
+                    if &remark[1..] == "////" {
+                        remark_parts.push(format!("{} precipitation not measured", period));
+                    } else if let Ok(hundredths) = remark[1..].parse::<u32>() {
+                        if hundredths == 0 {
+                            if remark.starts_with('7') {
+                                info.precip_24_hour_trace = true;
+                            } else {
+                                info.precip_period_trace = true;
+                            }
+                            remark_parts.push(format!("{} precipitation: trace", period));
+                        } else {
+                            let inches = hundredths as f32 / 100.0;
+                            if remark.starts_with('7') {
+                                info.precip_24_hour_inches = Some(inches);
+                            } else {
+                                info.precip_period_inches = Some(inches);
+                            }
+                            remark_parts.push(format!("{} precipitation: {} inches", period, options.format(inches as f64, 2)));
                         }
                     }
+                } else if let Some(types) = cloud_types(remark) {
+                    // cloud types by layer, like "8/578"
+                    let describe = |layer: &Option<String>| layer.clone().unwrap_or_else(|| "not observed".to_string());
+                    remark_parts.push(format!(
+                        "Cloud types: low {}, middle {}, high {}",
+                        describe(&types.low).to_lowercase(), describe(&types.middle).to_lowercase(), describe(&types.high).to_lowercase()
+                    ));
+                    info.cloud_types = Some(types);
+                } else if let Some(layer) = icing_layer(remark) {
+                    // coded icing, like "620304"
+                    remark_parts.push(layer.description.clone());
+                    info.icing.push(layer);
+                } else if let Some(layer) = turbulence_layer(remark) {
+                    // coded turbulence, like "540302"
+                    remark_parts.push(layer.description.clone());
+                    info.turbulence.push(layer);
+                } else if let Some((temp_c, dew_c)) = precise_temperature(remark) {
+                    // precise temperature/dewpoint in tenths of degrees, like "T01830122". it replaces the
+                    // rounded values from the body once the remarks are done rather than getting a line of its own
+                    let plausible = |celsius: f32| PLAUSIBLE_TEMPERATURE_C.contains(&(celsius.round() as i32));
+                    if plausible(temp_c) && dew_c.is_none_or(plausible) {
+                        info.temperature_precise_c = Some(temp_c);
+                        info.dewpoint_precise_c = dew_c;
+                    } else {
+                        info.warnings.push(format!("Temperature group {} is out of range and was ignored", remark));
+                    }
+                    applied = true;
                 } else if remark == "$" {
                     // maintenance indicator
                     remark_parts.push("Maintenance needed on automated station".to_string());
                 }
+
+                // group runs of unknown tokens together, ahead of whatever got decoded next
+                if remark_parts.len() == decoded_before && !applied {
+                    info.field_errors.push(FieldError::new(remark, "remarks", "Unknown remark, kept verbatim"));
+                    undecoded.push(remark);
+                } else if !undecoded.is_empty() {
+                    remark_parts.insert(decoded_before, undecoded.join(" "));
+                    undecoded.clear();
+                }
                 i += 1;
             }
+            if !undecoded.is_empty() {
+                remark_parts.push(undecoded.join(" "));
+            }
+            // a frontal passage with the pressure jump or peak wind that came with it reads better as one line, put first
+            if frontal_passage && (pressure_rising || peak_wind.is_some()) {
+                let mut events = vec![match &wind_shift_time {
+                    Some(time) => format!("wind shifted at {}", time),
+                    None => "wind shifted".to_string(),
+                }];
+                if pressure_rising {
+                    events.push("pressure rising rapidly".to_string());
+                }
+                if let Some(wind) = &peak_wind {
+                    events.push(format!("peak wind {}", wind));
+                }
+                let narrative = format!("Frontal passage: {}", events.join(", "));
+                remark_parts.insert(0, narrative.clone());
+                info.frontal_passage = Some(narrative);
+            }
             if !remark_parts.is_empty() {
                 info.remarks = remark_parts.join(". ");
             }
@@ -393,10 +947,962 @@ pub fn parse_metar(metar: &str, icao: &str) -> MetarInfo {
             // no significant changes or maintenance indicator
             i += 1;
         } else {
+            info.field_errors.push(FieldError::new(part, "body", "Not a temperature, altimeter or remarks group"));
             i += 1;
         }
     }
 
+    // with a precipitation sensor down, no weather in the report doesn't mean no precipitation
+    let precipitation_sensors = [
+        info.sensors.precipitation_identifier,
+        info.sensors.precipitation_gauge,
+        info.sensors.freezing_rain,
+    ];
+    info.precipitation_unavailable = precipitation_sensors.contains(&Some(SensorState::Inoperative));
+    if info.precipitation_unavailable && (info.weather == "None" || info.weather == "None significant") {
+        info.weather = "None reported, precipitation data unavailable".to_string();
+    }
+
+    // the precise values from the remarks stand in for the whole degrees in the body
+    if let Some(temp_c) = info.temperature_precise_c {
+        info.temperature = describe_precise_temperature(temp_c, options);
+    }
+    if let Some(dew_c) = info.dewpoint_precise_c {
+        info.dewpoint = describe_precise_temperature(dew_c, options);
+    }
+
+    // values worked out from the decoded groups rather than read from the report, each can be switched off
+    let derived = &options.derived;
+    let temperature = info.temperature_precise_c.map(f64::from).or(info.temperature_c.map(f64::from));
+    let dewpoint = info.dewpoint_precise_c.map(f64::from).or(info.dewpoint_c.map(f64::from));
+    if let (Some(temp_c), Some(dew_c)) = (temperature, dewpoint) {
+        let humidity = relative_humidity(temp_c, dew_c);
+        if derived.humidity {
+            info.humidity_percent = Some(humidity.round() as u32);
+        }
+        if derived.feels_like {
+            info.feels_like_c = feels_like_c(temp_c, info.wind_speed_knots.unwrap_or(0) as f64, humidity)
+                .map(|feels_like| feels_like.round() as i32);
+        }
+    }
+    if sky_reported && derived.flight_category {
+        info.flight_category = info
+            .visibility_miles
+            .map(|miles| flight_category(info.ceiling_feet, miles).to_string());
+    }
+    if derived.limiting_factor {
+        let wind_knots = info.wind_gust_knots.or(info.wind_speed_knots);
+        info.limiting_factor = limiting_factor(info.ceiling_feet, info.visibility_miles, wind_knots, significant_weather)
+            .map(str::to_string);
+    }
+    // the ceiling is still needed for the flight category and limiting factor even when it isn't reported itself
+    if !derived.ceiling {
+        info.ceiling_feet = None;
+    }
+
+    // how much of the core report got decoded, so odd stations and feeds stand out
+    let core_fields = [
+        ("wind", !info.wind.is_empty()),
+        ("visibility", !info.visibility.is_empty()),
+        ("clouds", !info.clouds.is_empty() && info.clouds != "No cloud information"),
+        ("temperature", !info.temperature.is_empty()),
+        ("altimeter", !info.altimeter.is_empty()),
+    ];
+    info.missing_fields = core_fields
+        .iter()
+        .filter(|(_, decoded)| !decoded)
+        .map(|(name, _)| name.to_string())
+        .collect();
+    info.decode_completeness = (core_fields.len() - info.missing_fields.len()) as f32 / core_fields.len() as f32;
+
     info
 }
 
+// decodes a wind shift remark starting at "WSHFT", the time is "hhmm" or just "mm" past the hour,
+// and a trailing "FROPA" means the shift came with a frontal passage
+pub fn decode_wind_shift_remark(parts: &[&str]) -> (String, usize) {
+    let mut desc = "Wind shift".to_string();
+    let mut used = 1;
+
+    if let Some(time) = parts.get(used).and_then(|time| remark_time(time)) {
+        desc.push_str(&format!(" at {}", time));
+        used += 1;
+    }
+
+    if parts.get(used) == Some(&"FROPA") {
+        desc.push_str(" (frontal passage)");
+        used += 1;
+    }
+
+    (desc, used)
+}
+
+// the time in a remark like "WSHFT 1530" or "PK WND 28045/30", either the full time or minutes past the hour
+pub fn remark_time(time: &str) -> Option<String> {
+    if !time.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match time.len() {
+        4 => Some(format!("{}:{}Z", &time[0..2], &time[2..4])),
+        2 => Some(format!("{} minutes past the hour", time)),
+        _ => None,
+    }
+}
+
+// reads a "PK WND 28045/1530" remark into the peak wind's direction, speed and when it happened
+pub fn peak_wind_remark(parts: &[&str]) -> Option<(u32, u32, String, usize)> {
+    if parts.first() != Some(&"PK") || parts.get(1) != Some(&"WND") {
+        return None;
+    }
+    let (wind, time) = parts.get(2)?.split_once('/')?;
+    if !(5..=6).contains(&wind.len()) || !wind.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let dir = wind[0..3].parse::<u32>().ok()?;
+    let speed = wind[3..].parse::<u32>().ok()?;
+    Some((dir, speed, remark_time(time)?, 3))
+}
+
+// reads a "DENSITY ALT 1200FT" remark, the height can be negative on cold days at low fields
+pub fn density_altitude_remark(parts: &[&str]) -> Option<(i32, usize)> {
+    if parts.first() != Some(&"DENSITY") || parts.get(1) != Some(&"ALT") {
+        return None;
+    }
+    let feet = parts.get(2)?.strip_suffix("FT")?.parse::<i32>().ok()?;
+    Some((feet, 3))
+}
+
+// decodes "SNINCR 2/10" (snow depth up 2 inches in the past hour, 10 inches on the ground),
+// a bare "SNINCR" still says the snow is increasing rapidly
+pub fn decode_snow_increase_remark(parts: &[&str]) -> Option<(String, usize)> {
+    if parts.first() != Some(&"SNINCR") {
+        return None;
+    }
+    let depths = parts.get(1).and_then(|group| {
+        let (hour, total) = group.split_once('/')?;
+        Some((hour.parse::<u32>().ok()?, total.parse::<u32>().ok()?))
+    });
+    match depths {
+        Some((hour, total)) => Some((
+            format!("Snow increasing rapidly, {} inches in the past hour, {} inches on the ground", hour, total),
+            2,
+        )),
+        None => Some(("Snow increasing rapidly".to_string(), 1)),
+    }
+}
+
+// decodes a ceiling remark starting at "CIG": a single height ("CIG 015"), a variable one ("CIG 005V010"),
+// either of which can be followed by where it was measured ("CIG 002 RWY11"). heights are hundreds of feet
+pub fn decode_ceiling_remark(parts: &[&str]) -> Option<(String, usize)> {
+    if parts.first() != Some(&"CIG") {
+        return None;
+    }
+    let height = |value: &str| -> Option<u32> {
+        if value.len() != 3 || !value.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        value.parse::<u32>().ok().map(|hundreds| hundreds * 100)
+    };
+
+    let group = parts.get(1)?;
+    let mut desc = match group.split_once('V') {
+        Some((low, high)) => format!("Ceiling variable between {} and {} feet", height(low)?, height(high)?),
+        None => format!("Ceiling {} feet", height(group)?),
+    };
+    let mut used = 2;
+
+    if let Some(location) = parts.get(used).and_then(|next| sensor_location(next)) {
+        desc.push_str(&format!(" ({})", location));
+        used += 1;
+    }
+
+    Some((desc, used))
+}
+
+// decodes a thunderstorm remark starting at "TS", returns the text and how many tokens it used
+pub fn decode_thunderstorm_remark(parts: &[&str]) -> (String, usize) {
+    let mut desc = "Thunderstorm".to_string();
+    let mut used = 1;
+
+    if used < parts.len() {
+        if parts[used] == "OHD" {
+            desc.push_str(" overhead");
+            used += 1;
+        } else if let Some(dir) = expand_direction(parts[used]) {
+            desc.push_str(&format!(" to the {}", dir));
+            used += 1;
+        }
+    }
+
+    // "MOV" gives the direction the storm is moving towards
+    if used + 1 < parts.len() && parts[used] == "MOV" {
+        if let Some(dir) = expand_direction(parts[used + 1]) {
+            desc.push_str(&format!(", moving {}", dir));
+            used += 2;
+        }
+    }
+
+    (desc, used)
+}
+
+// where a phenomenon in the remarks was seen, from the tokens after it: "OHD", "VC", "ALQDS", a direction
+// like "SW" or "NE-E", or "DSNT" with an optional direction. returns the phrase and how many tokens it used
+pub fn phenomenon_location(parts: &[&str]) -> Option<(String, usize)> {
+    match *parts.first()? {
+        "OHD" => Some(("overhead".to_string(), 1)),
+        "VC" => Some(("in the vicinity".to_string(), 1)),
+        "ALQDS" => Some(("in all quadrants".to_string(), 1)),
+        "DSNT" => match parts.get(1).and_then(|part| expand_direction(part)) {
+            Some(dir) => Some((format!("in the distance to the {}", dir), 2)),
+            None => Some(("in the distance".to_string(), 1)),
+        },
+        part => expand_direction(part).map(|dir| (format!("to the {}", dir), 1)),
+    }
+}
+
+// the kinds of lightning that can follow "LTG" in one token, like "LTGICCG"
+const LIGHTNING_TYPES: &[(&str, &str)] = &[
+    ("IC", "in-cloud"),
+    ("CC", "cloud-to-cloud"),
+    ("CG", "cloud-to-ground"),
+    ("CA", "cloud-to-air"),
+];
+
+// decodes a lightning remark like "OCNL LTG SW" or "FRQ LTGICCG DSNT ALQDS", returns the text and how many tokens it used
+pub fn decode_lightning_remark(parts: &[&str]) -> (String, usize) {
+    let mut used = 0;
+
+    let frequency = match parts.first() {
+        Some(&"FRQ") => Some("Frequent"),
+        Some(&"OCNL") => Some("Occasional"),
+        Some(&"CONS") => Some("Continuous"),
+        _ => None,
+    };
+    if frequency.is_some() {
+        used += 1;
+    }
+
+    // the type codes come in pairs straight after "LTG", anything unrecognised is left out
+    let codes = parts.get(used).and_then(|part| part.strip_prefix("LTG")).unwrap_or_default();
+    let types: Vec<&str> = codes
+        .as_bytes()
+        .chunks(2)
+        .filter_map(|pair| LIGHTNING_TYPES.iter().find(|(code, _)| code.as_bytes() == pair).map(|(_, name)| *name))
+        .collect();
+    let lightning = match types.split_last() {
+        Some((last, [])) => format!("{} lightning", last),
+        Some((last, rest)) => format!("{} and {} lightning", rest.join(", "), last),
+        None => "lightning".to_string(),
+    };
+
+    let mut desc = match frequency {
+        Some(freq) => format!("{} {}", freq, lightning),
+        None => {
+            let mut chars = lightning.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars).collect()).unwrap_or_default()
+        }
+    };
+    used += 1;
+
+    // a location can be more than one group, like "DSNT ALQDS"
+    while let Some((location, location_used)) = parts.get(used..).and_then(phenomenon_location) {
+        desc.push_str(&format!(" {}", location));
+        used += location_used;
+    }
+
+    (desc, used)
+}
+
+// the run of digits at the start of a cloud group's height, "030" from "030CB"
+pub fn layer_digits(height: &str) -> String {
+    height.chars().take_while(|c| c.is_ascii_digit()).collect()
+}
+
+// " (300 meters)" after a layer height that was reported in meters
+pub fn meters_note(meters: Option<u32>) -> String {
+    meters.map(|m| format!(" ({} meters)", m)).unwrap_or_default()
+}
+
+// a temperature from the precise remark group, to one decimal in both units and marked as coming from the remarks
+pub fn describe_precise_temperature(celsius: f32, options: &DecodeOptions) -> String {
+    let fahrenheit = celsius as f64 * 9.0 / 5.0 + 32.0;
+    format!(
+        "{}°C ({}°F), precise value from the remarks",
+        options.format(celsius as f64, 1), options.format(fahrenheit, 1)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // decodes a report with the default options
+    fn decode(raw: &str) -> MetarInfo {
+        parse_metar(raw, "KJFK", &DecodeOptions::default())
+    }
+
+    #[test]
+    fn last_observation_remark() {
+        let info = decode("KJFK 160551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 LAST");
+        assert_eq!(info.remarks, "Automated station. Last observation of the period");
+    }
+
+    #[test]
+    fn first_observation_remark() {
+        let info = decode("KJFK 161151Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 FIRST");
+        assert_eq!(info.remarks, "Automated station. First observation of the period");
+    }
+
+    #[test]
+    fn thunderstorm_location_and_movement() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK TS SE MOV NE");
+        assert_eq!(info.remarks, "Thunderstorm to the southeast, moving northeast");
+    }
+
+    #[test]
+    fn unknown_remarks_are_kept_verbatim_in_order() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 SFC VIS 2 XYZ LAST QQQ");
+        assert_eq!(info.remarks, "Automated station. SFC VIS 2 XYZ. Last observation of the period. QQQ");
+    }
+
+    #[test]
+    fn metric_visibility_with_ndv() {
+        let info = decode("KJFK 161551Z 28010KT 5000NDV FEW040 12/M02 A2990");
+        assert_eq!(info.visibility, "5 kilometers, no directional variation");
+
+        let info = decode("KJFK 161551Z 28010KT 1200NDV FEW040 12/M02 A2990");
+        assert!(info.visibility.ends_with(", no directional variation"));
+        assert!((info.visibility_miles.unwrap() - 0.75).abs() < 0.01);
+        assert_eq!(info.clouds, "Few at 4000 feet");
+    }
+
+    #[tokio::test]
+    async fn upstream_check_uses_the_configured_url() {
+        let good = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        assert!(check_upstream(&good.url, &DecodeOptions::default()).await.is_ok());
+        assert_eq!(good.hits(), 1);
+
+        let broken = test_support::upstream(reqwest::StatusCode::INTERNAL_SERVER_ERROR, "text/plain", "oops").await;
+        assert!(check_upstream(&broken.url, &DecodeOptions::default()).await.is_err());
+        assert_eq!(broken.hits(), 1);
+
+        let garbage = test_support::metar_upstream("not a metar at all").await;
+        assert!(check_upstream(&garbage.url, &DecodeOptions::default()).await.is_err());
+    }
+
+    #[test]
+    fn precipitation_groups() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK 60009 70125");
+        assert_eq!(info.remarks, "3/6-hour precipitation: 0.09 inches. 24-hour precipitation: 1.25 inches");
+        assert_eq!(info.precip_period_inches, Some(0.09));
+        assert_eq!(info.precip_24_hour_inches, Some(1.25));
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK 6////");
+        assert_eq!(info.remarks, "3/6-hour precipitation not measured");
+        assert_eq!(info.precip_period_inches, None);
+        assert!(!info.precip_period_trace);
+    }
+
+    #[test]
+    fn precipitation_period_follows_the_report_time() {
+        let info = decode("KJFK 161151Z 28010KT 10SM FEW040 12/M02 A2990 RMK 60012");
+        assert_eq!(info.precip_period_hours, Some(6));
+        assert_eq!(info.remarks, "6-hour precipitation: 0.12 inches");
+
+        let info = decode("KJFK 161451Z 28010KT 10SM FEW040 12/M02 A2990 RMK 60012");
+        assert_eq!(info.precip_period_hours, Some(3));
+        assert_eq!(info.remarks, "3-hour precipitation: 0.12 inches");
+    }
+
+    #[test]
+    fn precipitation_trace_has_no_amount() {
+        let info = decode("KJFK 161151Z 28010KT 10SM FEW040 12/M02 A2990 RMK 60000 70000");
+        assert_eq!(info.remarks, "6-hour precipitation: trace. 24-hour precipitation: trace");
+        assert_eq!(info.precip_period_inches, None);
+        assert!(info.precip_period_trace);
+        assert_eq!(info.precip_24_hour_inches, None);
+        assert!(info.precip_24_hour_trace);
+    }
+
+    #[tokio::test]
+    async fn oversized_upstream_body_is_refused() {
+        let oversized = format!("{} {}", test_support::SAMPLE_METAR, "X".repeat(MAX_RESPONSE_BYTES));
+        let upstream = test_support::metar_upstream(&oversized).await;
+        let error = fetch_metar(&upstream.url, "KJFK").await.unwrap_err();
+        assert!(error.to_string().contains("too large"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn oversized_chunked_body_is_refused() {
+        // no content-length, so the limit has to catch it while reading
+        let chunk = "X".repeat(16 * 1024);
+        let mut response = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n".to_string();
+        for _ in 0..8 {
+            response.push_str(&format!("{:x}\r\n{}\r\n", chunk.len(), chunk));
+        }
+        response.push_str("0\r\n\r\n");
+        let url = test_support::raw_upstream(response.into_bytes()).await;
+        let error = fetch_metar(&url, "KJFK").await.unwrap_err();
+        assert!(error.to_string().contains("larger than"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn body_under_the_limit_is_read() {
+        let upstream = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        assert_eq!(fetch_metar(&upstream.url, "KJFK").await.unwrap(), test_support::SAMPLE_METAR);
+    }
+
+    #[test]
+    fn altimeter_in_both_units() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2992");
+        assert_eq!(info.altimeter_hpa, Some(1013));
+        assert_eq!(info.altimeter_inhg, Some(29.92));
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2953");
+        assert_eq!(info.altimeter_hpa, Some(1000));
+    }
+
+    #[test]
+    fn unmeasured_wind() {
+        let info = decode("KJFK 161551Z 270//KT 10SM FEW040 12/M02 A2990");
+        assert_eq!(info.wind, "270 degrees (W), wind speed not measured");
+        assert_eq!(info.wind_dir_degrees, Some(270));
+        assert_eq!(info.wind_speed_knots, None);
+
+        let info = decode("KJFK 161551Z ////KT 10SM FEW040 12/M02 A2990");
+        assert_eq!(info.wind, "Wind not measured");
+        assert_eq!(info.wind_dir_degrees, None);
+        assert_eq!(info.visibility, "10 statute miles");
+    }
+
+    #[test]
+    fn short_wind_groups_dont_panic() {
+        for wind in ["KT", "1KT", "//KT", "VRBKT", "VRBGKT", "270KT", "27010G"] {
+            decode(&format!("KJFK 161551Z {} 10SM FEW040 12/M02 A2990", wind));
+        }
+    }
+
+    #[test]
+    fn not_available_sentinels() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 SLPNO PNO");
+        assert_eq!(info.remarks, "Automated station. Sea-level pressure not available. Hourly precipitation not available");
+        assert_eq!(info.warnings, ["Sea-level pressure not available", "Hourly precipitation not available"]);
+    }
+
+    #[test]
+    fn wind_shift_remark() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 WSHFT 1530 FROPA");
+        assert_eq!(info.remarks, "Automated station. Wind shift at 15:30Z (frontal passage)");
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK WSHFT 1530");
+        assert_eq!(info.remarks, "Wind shift at 15:30Z");
+    }
+
+    #[test]
+    fn temperature_group_in_the_cloud_position() {
+        let info = decode("KJFK 161551Z 28010KT 10SM 12/M02 FEW040 A2990");
+        assert_eq!(info.temperature, "12°C (53°F)");
+        assert_eq!(info.dewpoint, "-2°C (29°F)");
+        assert_eq!(info.altimeter, "29.90 inches of mercury");
+    }
+
+    #[test]
+    fn variable_cloud_coverage_remark() {
+        let info = decode("KJFK 161551Z 28010KT 10SM SCT015 12/M02 A2990 RMK SCT015 V BKN");
+        assert_eq!(info.remarks, "Scattered variable broken at 1500 feet");
+    }
+
+    #[tokio::test]
+    async fn html_error_page_is_refused() {
+        let page = "<!DOCTYPE html><html><head><title>Just a moment...</title></head><body>Checking your browser</body></html>";
+        let upstream = test_support::upstream(reqwest::StatusCode::OK, "text/html; charset=utf-8", page).await;
+        let error = fetch_metar(&upstream.url, "KJFK").await.unwrap_err();
+        assert_eq!(error.to_string(), "Upstream returned an unexpected format (HTML page)");
+
+        // caught by the body even when it claims to be plain text
+        let upstream = test_support::upstream(reqwest::StatusCode::OK, "text/plain", page).await;
+        let error = fetch_metar(&upstream.url, "KJFK").await.unwrap_err();
+        assert_eq!(error.to_string(), "Upstream returned an unexpected format (HTML page)");
+    }
+
+    #[test]
+    fn aircraft_mishap_remark() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 ACFT MSHP");
+        assert_eq!(info.remarks, "Automated station. Aircraft mishap");
+    }
+
+    #[test]
+    fn variable_wind_range_is_numeric() {
+        let info = decode("KJFK 161551Z 24015KT 200V280 10SM FEW040 12/M02 A2990");
+        assert_eq!(info.wind_dir_from, Some(200));
+        assert_eq!(info.wind_dir_to, Some(280));
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["wind_dir_from"], 200);
+        assert_eq!(json["wind_dir_to"], 280);
+
+        let info = decode(test_support::SAMPLE_METAR);
+        assert_eq!((info.wind_dir_from, info.wind_dir_to), (None, None));
+    }
+
+    #[test]
+    fn computed_values_follow_the_configured_precision() {
+        let raw = "KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 P0009 T01220017";
+        let info = decode(raw);
+        assert!(info.temperature.starts_with("12.2°C (54.0°F)"), "{}", info.temperature);
+        assert!(info.remarks.contains("Precipitation: 0.09 inches"), "{}", info.remarks);
+
+        let options = DecodeOptions {
+            precision: Some(3),
+            ..DecodeOptions::default()
+        };
+        let info = parse_metar(raw, "KJFK", &options);
+        assert!(info.temperature.starts_with("12.200°C (53.960°F)"), "{}", info.temperature);
+        assert!(info.remarks.contains("Precipitation: 0.090 inches"), "{}", info.remarks);
+    }
+
+    #[test]
+    fn wind_above_99_knots() {
+        let info = decode("KJFK 161551Z 240P99KT 10SM FEW040 12/M02 A2990");
+        assert_eq!(info.wind, "240 degrees (SW) at greater than 99 knots");
+        assert_eq!(info.wind_speed_knots, Some(99));
+        assert!(info.wind_speed_above);
+        assert!(!info.wind_gust_above);
+
+        let info = decode("KJFK 161551Z 24015GP99KT 10SM FEW040 12/M02 A2990");
+        assert_eq!(info.wind, "240 degrees (SW) at 15 knots, gusting to greater than 99 knots");
+        assert_eq!(info.wind_gust_knots, Some(99));
+        assert!(!info.wind_speed_above);
+        assert!(info.wind_gust_above);
+    }
+
+    #[test]
+    fn secondary_ceiling_sensor_outage() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 CHINO RWY24");
+        assert_eq!(info.remarks, "Automated station. Secondary ceiling sensor (runway 24) not available");
+        assert_eq!(info.sensors.secondary_ceiling, Some(SensorState::Inoperative));
+        assert!(info.sensors.secondary_visibility.is_none());
+    }
+
+    #[test]
+    fn altimeter_json_has_both_units_from_either_source() {
+        let info = parse_metar("EGLL 161550Z 28010KT 9999 FEW040 12/M02 Q1013", "EGLL", &DecodeOptions::default());
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(json.contains(r#""altimeter_hpa":1013,"altimeter_inhg":29.91,"altimeter_default_unit":"hpa""#), "{}", json);
+
+        let json = serde_json::to_string(&decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2992")).unwrap();
+        assert!(json.contains(r#""altimeter_hpa":1013,"altimeter_inhg":29.92,"altimeter_default_unit":"inches""#), "{}", json);
+    }
+
+    #[test]
+    fn variable_range_ahead_of_the_wind_group() {
+        let info = decode("KJFK 161551Z 200V280 24015KT 10SM FEW040 12/M02 A2990");
+        assert_eq!(info.wind, "240 degrees (SW) at 15 knots, variable between 200 and 280 degrees");
+        assert_eq!((info.wind_dir_from, info.wind_dir_to), (Some(200), Some(280)));
+        assert_eq!(info.visibility, "10 statute miles");
+    }
+
+    #[test]
+    fn ceiling_remark_forms() {
+        let remarks = |rmk: &str| decode(&format!("KJFK 161551Z 28010KT 10SM BKN015 12/M02 A2990 RMK {}", rmk)).remarks;
+        assert_eq!(remarks("CIG 015"), "Ceiling 1500 feet");
+        assert_eq!(remarks("CIG 005V010"), "Ceiling variable between 500 and 1000 feet");
+        assert_eq!(remarks("CIG 002 RWY11"), "Ceiling 200 feet (runway 11)");
+    }
+
+    #[test]
+    fn density_altitude_remark() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 DENSITY ALT 1200FT");
+        assert_eq!(info.remarks, "Automated station. Density altitude 1200 feet");
+        assert_eq!(info.density_altitude_feet, Some(1200));
+    }
+
+    #[test]
+    fn sensor_states_for_ao1_and_tsno() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO1");
+        assert_eq!(info.sensors.precipitation_identifier, Some(SensorState::NotInstalled));
+        assert!(info.sensors.lightning.is_none());
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 TSNO");
+        assert_eq!(info.sensors.precipitation_identifier, Some(SensorState::Operational));
+        assert_eq!(info.sensors.lightning, Some(SensorState::Inoperative));
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["sensors"]["lightning"], "inoperative");
+        assert_eq!(json["sensors"]["precipitation_identifier"], "operational");
+    }
+
+    #[test]
+    fn directional_minimum_visibility() {
+        let info = parse_metar("EGLL 161550Z 28010KT 9999 2000SW FEW040 12/M02 Q1013", "EGLL", &DecodeOptions::default());
+        assert_eq!(info.visibility, "10 kilometers or more, down to 2000 meters to the southwest");
+        assert_eq!(info.clouds, "Few at 4000 feet");
+    }
+
+    #[test]
+    fn completeness_of_full_and_sparse_reports() {
+        let info = decode(test_support::SAMPLE_METAR);
+        assert_eq!(info.decode_completeness, 1.0);
+        assert!(info.missing_fields.is_empty());
+
+        let info = decode("KJFK 161551Z 28010KT A2990");
+        assert_eq!(info.decode_completeness, 0.4);
+        assert_eq!(info.missing_fields, vec!["visibility", "clouds", "temperature"]);
+    }
+
+    #[test]
+    fn frost_on_the_indicator_remark() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 M02/M04 A2990 RMK AO2 FROIN");
+        assert_eq!(info.remarks, "Automated station. Frost on the indicator");
+    }
+
+    #[test]
+    fn lowercase_report_decodes_like_uppercase() {
+        let lower = decode("kjfk 161551z 28010kt 10sm few040 12/m02 a2990 rmk ao2");
+        let upper = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2");
+        assert_eq!(lower.station, "KJFK");
+        assert_eq!(lower.wind, upper.wind);
+        assert_eq!(lower.clouds, upper.clouds);
+        assert_eq!(lower.temperature, upper.temperature);
+        assert_eq!(lower.dewpoint, "-2°C (29°F)");
+        assert_eq!(lower.altimeter, upper.altimeter);
+        assert_eq!(lower.remarks, "Automated station");
+    }
+
+    #[test]
+    fn only_the_listed_derived_fields() {
+        let options = DecodeOptions {
+            derived: crate::models::DerivedFields::from_list("flight_category"),
+            ..DecodeOptions::default()
+        };
+        let info = parse_metar("KJFK 161551Z 28010KT 3SM BR BKN008 12/10 A2990", "KJFK", &options);
+        assert_eq!(info.flight_category.as_deref(), Some("IFR"));
+        assert_eq!(info.ceiling_feet, None);
+        assert_eq!(info.humidity_percent, None);
+        assert_eq!(info.feels_like_c, None);
+        assert_eq!(info.limiting_factor, None);
+
+        let info = decode("KJFK 161551Z 28010KT 3SM BR BKN008 12/10 A2990");
+        assert_eq!(info.ceiling_feet, Some(800));
+        assert!(info.humidity_percent.is_some());
+
+        // the reported density altitude is read from the remarks, so it isn't switched off with the derived values
+        let options = DecodeOptions {
+            derived: crate::models::DerivedFields::from_list("none"),
+            ..DecodeOptions::default()
+        };
+        let info = parse_metar("KJFK 161551Z 28010KT 10SM FEW040 32/10 A2990 RMK DENSITY ALT 4500FT", "KJFK", &options);
+        assert_eq!(info.density_altitude_feet, Some(4500));
+        assert_eq!(info.humidity_percent, None);
+    }
+
+    #[test]
+    fn cloud_heights_in_meters() {
+        let raw = "UUEE 161550Z 28005MPS 9999 BKN300 12/M02 Q1013";
+        assert_eq!(parse_metar(raw, "UUEE", &DecodeOptions::default()).clouds, "Broken at 30000 feet");
+
+        let options = DecodeOptions {
+            cloud_heights_meters: true,
+            ..DecodeOptions::default()
+        };
+        let info = parse_metar(raw, "UUEE", &options);
+        assert_eq!(info.clouds, "Broken at 984 feet (300 meters)");
+        assert_eq!(info.ceiling_feet, Some(984));
+
+        // four digits are always meters
+        let info = parse_metar("UUEE 161550Z 28005MPS 9999 OVC0450 12/M02 Q1013", "UUEE", &DecodeOptions::default());
+        assert_eq!(info.clouds, "Overcast at 1476 feet (450 meters)");
+    }
+
+    #[test]
+    fn ragged_ceiling_remark() {
+        let info = decode("KJFK 161551Z 28010KT 10SM BKN015 12/M02 A2990 RMK AO2 CIG RAG");
+        assert_eq!(info.remarks, "Automated station. Ragged ceiling");
+    }
+
+    #[test]
+    fn unmeasured_and_metric_visibility() {
+        let info = parse_metar("EGLL 161550Z 28010KT //// FEW040 12/M02 Q1013", "EGLL", &DecodeOptions::default());
+        assert_eq!(info.visibility, "Visibility not measured");
+        assert_eq!(info.visibility_miles, None);
+        assert_eq!(info.clouds, "Few at 4000 feet");
+
+        let info = parse_metar("EGLL 161550Z 28010KT 0350 FEW040 12/M02 Q1013", "EGLL", &DecodeOptions::default());
+        assert_eq!(info.visibility, "350 meters");
+        assert!((info.visibility_miles.unwrap() - 0.2175).abs() < 0.001);
+    }
+
+    #[test]
+    fn calm_wind_spellings() {
+        for raw in [
+            "KJFK 161551Z 00000KT 10SM CLR 12/M02 A2990",
+            "KJFK 161551Z CALM 10SM CLR 12/M02 A2990",
+        ] {
+            let info = decode(raw);
+            assert_eq!(info.wind, "Calm", "{raw}");
+            assert!(info.is_calm, "{raw}");
+            assert_eq!(info.visibility, "10 statute miles", "{raw}");
+        }
+    }
+
+    #[test]
+    fn cloud_type_remark() {
+        let types = decode("EGLL 161550Z 28010KT 9999 FEW040 12/M02 Q1013 RMK 8/578").cloud_types.unwrap();
+        assert_eq!(types.low.as_deref(), Some("Stratocumulus"));
+        assert_eq!(types.middle.as_deref(), Some("Thick or layered altocumulus"));
+        assert_eq!(types.high.as_deref(), Some("Cirrostratus not covering the sky"));
+
+        let info = decode("EGLL 161550Z 28010KT 9999 FEW040 12/M02 Q1013 RMK 8/6//");
+        let types = info.cloud_types.unwrap();
+        assert_eq!(types.low.as_deref(), Some("Stratus"));
+        assert_eq!(types.middle, None);
+        assert_eq!(types.high, None);
+        assert!(info.remarks.contains("middle not observed, high not observed"));
+    }
+
+    #[test]
+    fn precise_temperature_refines_the_body_group() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 18/12 A2990 RMK AO2 T01830122");
+        assert_eq!(info.temperature, "18.3°C (64.9°F), precise value from the remarks");
+        assert_eq!(info.dewpoint, "12.2°C (54.0°F), precise value from the remarks");
+        assert_eq!(info.temperature_c, Some(18));
+        assert_eq!(info.dewpoint_c, Some(12));
+        assert_eq!(info.temperature_precise_c, Some(18.3));
+        assert_eq!(info.dewpoint_precise_c, Some(12.2));
+        assert!(!info.remarks.contains("°C"));
+    }
+
+    #[test]
+    fn metric_runway_visual_range() {
+        let info = decode("EGLL 161550Z 28010KT 0800 R25/0650U R25/0600V1200N FG OVC002 12/12 Q1013");
+        let rvr = &info.runway_visual_range;
+        assert_eq!(rvr.len(), 2);
+
+        assert_eq!(rvr[0].runway, "25");
+        assert_eq!((rvr[0].value, rvr[0].max_value), (650, None));
+        assert_eq!(rvr[0].unit, "meters");
+        assert_eq!(rvr[0].tendency.as_deref(), Some("increasing"));
+
+        assert_eq!((rvr[1].value, rvr[1].max_value), (600, Some(1200)));
+        assert_eq!(rvr[1].unit, "meters");
+        assert_eq!(rvr[1].tendency.as_deref(), Some("no change"));
+        assert_eq!(rvr[1].description, "Runway 25 visual range 600 meters to 1200 meters, no change");
+
+        let feet = decode("KJFK 161551Z 28010KT 1/2SM R04R/2000V4000FT FG OVC002 12/12 A2990");
+        assert_eq!(feet.runway_visual_range[0].unit, "feet");
+    }
+
+    // an upstream that redirects every request to the location
+    async fn redirecting_upstream(location: String) -> String {
+        let router = axum::Router::new()
+            .route("/report", axum::routing::get(|| async { test_support::SAMPLE_METAR }))
+            .fallback(move || {
+                let location = location.clone();
+                async move { axum::response::Redirect::temporary(&location) }
+            });
+        test_support::spawn_server(router).await
+    }
+
+    #[tokio::test]
+    async fn override_follows_redirects_on_its_own_host_only() {
+        let same_host = redirecting_upstream("/report".to_string()).await;
+        assert_eq!(fetch_override_metar(&same_host, "KJFK").await.unwrap(), test_support::SAMPLE_METAR);
+
+        let elsewhere = test_support::metar_upstream(test_support::SAMPLE_METAR).await;
+        let off_host = redirecting_upstream(elsewhere.url.clone()).await;
+        let error = fetch_override_metar(&off_host, "KJFK").await.unwrap_err();
+        assert_eq!(error.to_string(), OFF_HOST_REDIRECT_MESSAGE);
+        assert_eq!(elsewhere.hits(), 0);
+
+        // the configured upstream still follows it
+        assert_eq!(fetch_metar(&off_host, "KJFK").await.unwrap(), test_support::SAMPLE_METAR);
+    }
+
+    #[test]
+    fn virga_and_shower_locations() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 VIRGA SW");
+        assert_eq!(info.remarks, "Automated station. Virga to the southwest");
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK SH VC VIRGA");
+        assert_eq!(info.remarks, "Showers in the vicinity. Virga (precipitation not reaching the ground)");
+    }
+
+    #[tokio::test]
+    async fn maintenance_page_is_reported_as_unavailable() {
+        let notice = "<html><body><h1>Scheduled maintenance</h1><p>We'll be back shortly.</p></body></html>";
+        let down = test_support::upstream(reqwest::StatusCode::SERVICE_UNAVAILABLE, "text/html", notice).await;
+        let error = fetch_metar(&down.url, "KJFK").await.unwrap_err();
+        assert_eq!(error.to_string(), UPSTREAM_UNAVAILABLE_MESSAGE);
+
+        let landing = test_support::upstream(reqwest::StatusCode::OK, "text/html", notice).await;
+        let error = fetch_metar(&landing.url, "KJFK").await.unwrap_err();
+        assert_eq!(error.to_string(), UPSTREAM_UNAVAILABLE_MESSAGE);
+    }
+
+    #[tokio::test]
+    async fn redirect_chains_are_followed_up_to_the_limit() {
+        use axum::{response::Redirect, routing::get};
+
+        let router = axum::Router::new()
+            .route("/first", get(|| async { Redirect::permanent("/second") }))
+            .route("/second", get(|| async { Redirect::temporary("/report") }))
+            .route("/report", get(|| async { test_support::SAMPLE_METAR }))
+            .route("/loop", get(|| async { Redirect::temporary("/loop") }));
+        let base = test_support::spawn_server(router).await;
+
+        let metar = fetch_metar(&format!("{}/first", base), "KJFK").await.unwrap();
+        assert_eq!(metar, test_support::SAMPLE_METAR);
+
+        let error = fetch_metar(&format!("{}/loop", base), "KJFK").await.unwrap_err();
+        assert_eq!(error.to_string(), format!("Upstream redirected more than {} times", MAX_UPSTREAM_REDIRECTS));
+    }
+
+    #[test]
+    fn temperature_range_checks() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 M40/M45 A2990");
+        assert_eq!(info.temperature_c, Some(-40));
+        assert_eq!(info.dewpoint_c, Some(-45));
+        assert_eq!(info.temperature, "-40°C (-40°F)");
+        assert!(info.warnings.is_empty());
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 99/99 A2990");
+        assert_eq!(info.temperature_c, None);
+        assert_eq!(info.dewpoint_c, None);
+        assert_eq!(info.temperature, "");
+        assert_eq!(info.warnings, ["Temperature group 99/99 is out of range and was ignored"]);
+    }
+
+    #[test]
+    fn frontal_passage_narrative_from_all_three_remarks() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 PK WND 28045/1515 WSHFT 1530 FROPA PRESRR");
+        let narrative = "Frontal passage: wind shifted at 15:30Z, pressure rising rapidly, peak wind 280° at 45 kt";
+        assert_eq!(info.frontal_passage.as_deref(), Some(narrative));
+        assert!(info.remarks.starts_with(narrative));
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 PK WND 28045/1515");
+        assert_eq!(info.frontal_passage, None);
+    }
+
+    #[test]
+    fn speci_is_flagged_special() {
+        let info = decode("SPECI KJFK 161612Z 28010KT 2SM BR OVC008 12/10 A2990");
+        assert!(info.is_special);
+        assert_eq!(info.station, "KJFK");
+        assert_eq!(info.date_time, "Day 16, 16:12Z");
+
+        assert!(!decode(test_support::SAMPLE_METAR).is_special);
+    }
+
+    #[test]
+    fn low_visibility_is_the_limiting_factor() {
+        let info = decode("KJFK 161551Z 28010KT 1/2SM FG BKN030 12/12 A2990");
+        assert_eq!(info.limiting_factor.as_deref(), Some("visibility"));
+
+        let info = decode("KJFK 161551Z 28010KT 10SM OVC004 12/10 A2990");
+        assert_eq!(info.limiting_factor.as_deref(), Some("ceiling"));
+
+        assert_eq!(decode(test_support::SAMPLE_METAR).limiting_factor, None);
+    }
+
+    #[test]
+    fn lightning_frequency_types_and_quadrants() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 FRQ LTGICCG DSNT ALQDS");
+        assert_eq!(info.remarks, "Automated station. Frequent in-cloud and cloud-to-ground lightning in the distance in all quadrants");
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK LTG DSNT ALQDS");
+        assert_eq!(info.remarks, "Lightning in the distance in all quadrants");
+    }
+
+    #[test]
+    fn variable_wind_with_a_range() {
+        let info = decode("KJFK 161551Z VRB03KT 180V240 10SM FEW040 12/M02 A2990");
+        assert_eq!(info.wind, "Variable at 3 knots, varying between 180 and 240 degrees");
+        assert_eq!(info.wind_dir_degrees, None);
+        assert_eq!(info.wind_speed_knots, Some(3));
+        assert_eq!((info.wind_dir_from, info.wind_dir_to), (Some(180), Some(240)));
+        assert_eq!(info.visibility, "10 statute miles");
+    }
+
+    #[test]
+    fn pno_keeps_the_weather_from_reading_as_clear() {
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2 PNO");
+        assert!(info.precipitation_unavailable);
+        assert_eq!(info.weather, "None reported, precipitation data unavailable");
+
+        let info = decode("KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2");
+        assert!(!info.precipitation_unavailable);
+        assert_eq!(info.weather, "None");
+    }
+
+    #[test]
+    fn rising_temperature_trend() {
+        // newest first, as the upstream lists them
+        let reports: Vec<MetarInfo> = [
+            "KJFK 161551Z 28020KT 10SM FEW040 15/M02 A2984",
+            "KJFK 161451Z 28012KT 10SM FEW040 13/M02 A2987",
+            "KJFK 161351Z 28010KT 10SM FEW040 11/M02 A2990",
+        ]
+        .iter()
+        .map(|raw| decode(raw))
+        .collect();
+        let trend = compute_trend(&reports).unwrap();
+        assert_eq!(trend.observations, 3);
+
+        let temperature = trend.temperature.unwrap();
+        assert_eq!(temperature.direction, TrendDirection::Rising);
+        assert_eq!(temperature.arrow, "↑");
+        assert_eq!(temperature.values, vec![11.0, 13.0, 15.0]);
+        assert_eq!(temperature.sparkline, "▁▅█");
+
+        assert_eq!(trend.pressure.unwrap().direction, TrendDirection::Falling);
+        assert_eq!(trend.wind.unwrap().direction, TrendDirection::Rising);
+
+        assert!(compute_trend(&reports[..1]).is_none());
+    }
+
+    #[test]
+    fn small_changes_are_steady() {
+        let values = vec![12.0, 12.4, 12.6];
+        let trend = value_trend(values, TEMPERATURE_TREND_STEP).unwrap();
+        assert_eq!(trend.direction, TrendDirection::Steady);
+        assert_eq!(trend.arrow, "→");
+        assert_eq!(value_trend(vec![3.0, 3.0], WIND_TREND_STEP).unwrap().sparkline, "▄▄");
+    }
+
+    #[test]
+    fn diff_of_a_wind_change() {
+        let previous = decode("KJFK 161451Z 28010KT 10SM FEW040 12/M02 A2990");
+        let latest = decode("KJFK 161551Z 30018G28KT 10SM FEW040 12/M02 A2990");
+        let changes = diff_reports(&previous, &latest);
+        let fields: Vec<&str> = changes.iter().map(|change| change.field).collect();
+        assert_eq!(fields, vec!["wind", "visibility", "clouds", "flight_category"]);
+
+        let wind = &changes[0];
+        assert!(wind.changed);
+        assert_eq!(wind.before, "280 degrees (W) at 10 knots");
+        assert_eq!(wind.after, "300 degrees (NW) at 18 knots, gusting to 28 knots");
+        assert_eq!(wind.delta, Some(8.0));
+
+        assert!(!changes[1].changed);
+        assert_eq!(changes[1].delta, Some(0.0));
+        // no ceiling in either report
+        assert_eq!(changes[2].delta, None);
+        assert!(!changes[3].changed);
+    }
+
+    #[test]
+    fn report_without_a_wind_group() {
+        let info = decode("KXYZ 161555Z AUTO 3SM BR OVC008 12/10 A2990 RMK AO2");
+        assert_eq!(info.wind, "");
+        assert_eq!(info.wind_speed_knots, None);
+        assert!(!info.is_calm);
+        assert_eq!(info.visibility, "3 statute miles");
+        assert_eq!(info.weather, "mist");
+        assert_eq!(info.clouds, "Overcast at 800 feet");
+        assert_eq!(info.missing_fields, ["wind"]);
+        assert!(info.warnings.is_empty());
+
+        let info = decode("KXYZ 161555Z AUTO 9999 OVC008 12/10 Q1013");
+        assert_eq!(info.visibility, "10 kilometers or more");
+        assert_eq!(info.missing_fields, ["wind"]);
+    }
+}
@@ -0,0 +1,104 @@
+// helpers shared by the tests: stand-in upstreams on a local port and requests sent straight to the router
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Request, StatusCode},
+    Router,
+};
+use tower::ServiceExt;
+use crate::config::Config;
+use crate::models::AppState;
+use crate::routes::create_router;
+
+// a report that decodes cleanly, for tests that only need something valid from the upstream
+pub const SAMPLE_METAR: &str = "KJFK 161551Z 28010KT 10SM FEW040 12/M02 A2990 RMK AO2";
+
+// a stand-in upstream and how many requests it has had
+pub struct Upstream {
+    pub url: String,
+    hits: Arc<AtomicUsize>,
+}
+
+impl Upstream {
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+// serves the router on a random local port and returns its base url
+pub async fn spawn_server(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{}", addr)
+}
+
+// an upstream that answers every request with the same status, content type and body
+pub async fn upstream(status: StatusCode, content_type: &'static str, body: impl Into<String>) -> Upstream {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let body = body.into();
+    let counter = hits.clone();
+    let router = Router::new().fallback(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        let body = body.clone();
+        async move { (status, [(header::CONTENT_TYPE, content_type)], body) }
+    });
+    Upstream {
+        url: spawn_server(router).await,
+        hits,
+    }
+}
+
+// an upstream serving the report as plain text, like aviationweather's raw format
+pub async fn metar_upstream(metar: &str) -> Upstream {
+    upstream(StatusCode::OK, "text/plain", metar).await
+}
+
+// the config with the upstream pointed at the given url
+pub fn config_with_source(url: &str) -> Config {
+    Config {
+        metar_source_url: url.to_string(),
+        ..Config::default()
+    }
+}
+
+// the whole app with the given config
+pub fn app(config: Config) -> Router {
+    create_router(Arc::new(AppState::new(config)))
+}
+
+// sends a request through the router, returns the status, headers and body
+pub async fn send(app: &Router, request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, headers, String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// a plain GET through the router
+pub async fn get(app: &Router, uri: &str) -> (StatusCode, HeaderMap, String) {
+    send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+// an upstream that writes the same raw http response to every connection, for responses axum won't build
+pub async fn raw_upstream(response: Vec<u8>) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let response = response.clone();
+            tokio::spawn(async move {
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(&response).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    format!("http://{}", addr)
+}
@@ -1,3 +1,5 @@
+use crate::models::{CloudTypes, HazardLayer, RunwayVisualRange};
+
 // converts wind direction in degrees to a cardinal direction like N, NE, E, etc.
 pub fn degrees_to_cardinal(degrees: u32) -> &'static str {
     match degrees {
@@ -13,6 +15,39 @@ pub fn degrees_to_cardinal(degrees: u32) -> &'static str {
     }
 }
 
+// parses a wind speed or gust field like "15" or "P99" (more than 99 knots), returns the speed and whether it's a lower bound
+pub fn parse_wind_speed(field: &str) -> Option<(u32, bool)> {
+    let (digits, above) = match field.strip_prefix('P') {
+        Some(rest) => (rest, true),
+        None => (field, false),
+    };
+    if digits.len() < 2 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse::<u32>().ok().map(|speed| (speed, above))
+}
+
+// parses a variable wind direction range like "200V250" into its two directions
+pub fn parse_wind_variation(code: &str) -> Option<(u32, u32)> {
+    let (from, to) = code.split_once('V')?;
+    let direction = |value: &str| {
+        if value.len() != 3 || !value.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        value.parse::<u32>().ok().filter(|degrees| *degrees <= 360)
+    };
+    Some((direction(from)?, direction(to)?))
+}
+
+// describes a wind speed like "15 knots" or "greater than 99 knots"
+pub fn describe_wind_speed(speed: u32, above: bool) -> String {
+    if above {
+        format!("greater than {} knots", speed)
+    } else {
+        format!("{} knots", speed)
+    }
+}
+
 // checks if a string contains a weather code like RA for rain or SN for snow
 pub fn is_weather_code(code: &str) -> bool {
     if code.starts_with("SKC") || code.starts_with("CLR") || code.starts_with("FEW") 
@@ -37,20 +72,155 @@ pub fn is_weather_code(code: &str) -> bool {
     false
 }
 
+// names a cloud coverage code like "SCT" (scattered) or "OVC" (overcast)
+pub fn cloud_coverage_name(code: &str) -> Option<&'static str> {
+    match code {
+        "FEW" => Some("Few"),
+        "SCT" => Some("Scattered"),
+        "BKN" => Some("Broken"),
+        "OVC" => Some("Overcast"),
+        _ => None,
+    }
+}
+
+// describes a statute-mile visibility (without the "SM") like "10", "1/2" or "1 1/2" and gives its value in miles.
+// here "M" means less than and "P" more than, unlike the temperature group where "M" is a minus sign
+pub fn describe_statute_miles(value: &str) -> Option<(String, f32)> {
+    let (amount, qualifier) = if let Some(rest) = value.strip_prefix('M') {
+        (rest, "Less than ")
+    } else if let Some(rest) = value.strip_prefix('P') {
+        (rest, "More than ")
+    } else {
+        (value, "")
+    };
+
+    let is_number = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_digit());
+    let is_fraction = |text: &str| matches!(text.split_once('/'), Some((top, bottom)) if is_number(top) && is_number(bottom));
+    let fraction_value = |text: &str| -> Option<f32> {
+        let (top, bottom) = text.split_once('/')?;
+        let bottom = bottom.parse::<f32>().ok().filter(|bottom| *bottom > 0.0)?;
+        Some(top.parse::<f32>().ok()? / bottom)
+    };
+    let (amount, miles) = match amount.split_once(' ') {
+        Some((whole, fraction)) if is_number(whole) && is_fraction(fraction) => {
+            (amount.to_string(), whole.parse::<f32>().ok()? + fraction_value(fraction)?)
+        }
+        None if is_fraction(amount) => (amount.to_string(), fraction_value(amount)?),
+        None if is_number(amount) => {
+            let whole = amount.parse::<u32>().ok()?;
+            (whole.to_string(), whole as f32)
+        }
+        _ => return None,
+    };
+    Some((format!("{}{} statute miles", qualifier, amount), miles))
+}
+
+// reads a directional visibility group like "2000SW" into the meters and the spelled-out direction
+pub fn directional_visibility(code: &str) -> Option<(u32, String)> {
+    let digits = code.get(0..4)?;
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // only a single direction, ranges like "NE-E" belong to remarks
+    let suffix = code.get(4..)?;
+    if suffix.contains('-') {
+        return None;
+    }
+    Some((digits.parse().ok()?, expand_direction(suffix)?))
+}
+
+// converts a visibility in meters to statute miles
+pub fn meters_to_statute_miles(meters: u32) -> f32 {
+    meters as f32 / 1609.344
+}
+
+// the height of a cloud layer from the digits after "BKN" or "VV", as (feet, meters when the report gave meters).
+// three digits are hundreds of feet unless the feed is known to use meters, four digits are always meters
+// since the standard group never has them
+pub fn cloud_layer_height(digits: &str, meters_feed: bool) -> Option<(u32, Option<u32>)> {
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let value = digits.parse::<u32>().ok()?;
+    match digits.len() {
+        3 if !meters_feed => Some((value * 100, None)),
+        3 | 4 => Some(((value as f64 * 3.28084).round() as u32, Some(value))),
+        _ => None,
+    }
+}
+
+// relative humidity in percent from the temperature and dewpoint, using the Magnus approximation
+pub fn relative_humidity(temp_c: f64, dew_c: f64) -> f64 {
+    let saturation = |celsius: f64| (17.625 * celsius / (243.04 + celsius)).exp();
+    (100.0 * saturation(dew_c) / saturation(temp_c)).clamp(0.0, 100.0)
+}
+
+// the apparent temperature when it differs from the air temperature: wind chill at 10°C or below
+// with some wind, heat index at 27°C or above in humid air. None when neither applies
+pub fn feels_like_c(temp_c: f64, wind_knots: f64, humidity: f64) -> Option<f64> {
+    let wind_kmh = wind_knots * 1.852;
+    if temp_c <= 10.0 && wind_kmh > 4.8 {
+        let factor = wind_kmh.powf(0.16);
+        return Some(13.12 + 0.6215 * temp_c - 11.37 * factor + 0.3965 * temp_c * factor);
+    }
+    if temp_c >= 27.0 && humidity >= 40.0 {
+        // the Rothfusz regression works in fahrenheit
+        let t = temp_c * 9.0 / 5.0 + 32.0;
+        let rh = humidity;
+        let heat_index = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh - 0.224_755_41 * t * rh
+            - 0.006_837_83 * t * t - 0.054_817_17 * rh * rh + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh - 0.000_001_99 * t * t * rh * rh;
+        return Some((heat_index - 32.0) * 5.0 / 9.0);
+    }
+    None
+}
+
+// the FAA flight category from the ceiling (None when there isn't one) and the visibility in statute miles,
+// whichever of the two is worse decides it
+pub fn flight_category(ceiling_feet: Option<u32>, visibility_miles: f32) -> &'static str {
+    let ceiling = ceiling_feet.unwrap_or(u32::MAX);
+    if ceiling < 500 || visibility_miles < 1.0 {
+        "LIFR"
+    } else if ceiling < 1000 || visibility_miles < 3.0 {
+        "IFR"
+    } else if ceiling <= 3000 || visibility_miles <= 5.0 {
+        "MVFR"
+    } else {
+        "VFR"
+    }
+}
+
+// checks if a token is a temperature/dewpoint group like "15/10", "M05/M10" or "15/" (dewpoint missing)
+pub fn is_temperature_group(code: &str) -> bool {
+    let Some((temp, dew)) = code.split_once('/') else {
+        return false;
+    };
+    let is_value = |value: &str| {
+        let digits = value.strip_prefix('M').unwrap_or(value);
+        digits.len() == 2 && digits.chars().all(|c| c.is_ascii_digit())
+    };
+    is_value(temp) && (dew.is_empty() || dew == "//" || is_value(dew))
+}
+
 // takes a weather code like -sn or +ra and converts it to readable text like "Light snow" or "Heavy rain"
 pub fn decode_weather(code: &str) -> String {
     let mut result = String::new();
     let mut i = 0;
     let chars: Vec<char> = code.chars().collect();
 
+    // intensity is held back until we know the phenomenon, it only means something for precipitation
+    // and dust/sand storms, so "-BR" is just mist rather than "light mist"
+    let mut intensity = "";
+    let mut intensity_applies = false;
+
     if i < chars.len() {
         match chars[i] {
             '-' => {
-                result.push_str("Light ");
+                intensity = "Light ";
                 i += 1;
             }
             '+' => {
-                result.push_str("Heavy ");
+                intensity = "Heavy ";
                 i += 1;
             }
             'V' if i + 1 < chars.len() && chars[i + 1] == 'C' => {
@@ -62,9 +232,11 @@ pub fn decode_weather(code: &str) -> String {
     }
 
     while i < chars.len() && chars[i].is_alphabetic() {
-        match &code[i..] {
+        // index by chars rather than bytes so odd input can't split a multi-byte character
+        let rest: String = chars[i..].iter().collect();
+        match rest.as_str() {
             s if s.starts_with("MI") => { result.push_str("Shallow "); i += 2; }
-            s if s.starts_with("BC") => { result.push_str("Patches "); i += 2; }
+            s if s.starts_with("BC") => { result.push_str("Patches of "); i += 2; }
             s if s.starts_with("DR") => { result.push_str("Low drifting "); i += 2; }
             s if s.starts_with("BL") => { result.push_str("Blowing "); i += 2; }
             s if s.starts_with("SH") => { result.push_str("Showers "); i += 2; }
@@ -76,6 +248,12 @@ pub fn decode_weather(code: &str) -> String {
     }
 
     while i < chars.len() && chars[i].is_alphabetic() {
+        if i + 1 < chars.len() {
+            let pair: String = chars[i..i + 2].iter().collect();
+            if matches!(pair.as_str(), "DZ" | "RA" | "SN" | "SG" | "IC" | "PL" | "GR" | "GS" | "UP" | "SS" | "DS" | "FC") {
+                intensity_applies = true;
+            }
+        }
         match chars[i] {
             'D' if i + 1 < chars.len() && chars[i + 1] == 'Z' => { result.push_str("drizzle"); i += 2; }
             'R' if i + 1 < chars.len() && chars[i + 1] == 'A' => { result.push_str("rain"); i += 2; }
@@ -103,20 +281,647 @@ pub fn decode_weather(code: &str) -> String {
         }
     }
 
+    if intensity_applies {
+        result.insert_str(0, intensity);
+    }
+
     result.trim().to_string()
 }
 
+// works out whether a "6RRRR" precipitation group covers 3 or 6 hours from the report time,
+// reports at 00, 06, 12 and 18Z cover 6 hours and those at 03, 09, 15 and 21Z cover 3.
+// reports a few minutes before the hour count as that hour
+pub fn precip_period_hours(hour: u32, minute: u32) -> Option<u32> {
+    let hour = if minute >= 45 { (hour + 1) % 24 } else { hour };
+    match hour % 6 {
+        0 => Some(6),
+        3 => Some(3),
+        _ => None,
+    }
+}
+
+// converts an altimeter setting in hundredths of an inch of mercury (like 2992) to hectopascals, rounded to the nearest whole hPa
+pub fn inhg_hundredths_to_hpa(hundredths: u32) -> u32 {
+    (hundredths as f64 * 0.338639).round() as u32
+}
+
+// converts hectopascals to inches of mercury, rounded to two decimals
+pub fn hpa_to_inhg(hpa: u32) -> f32 {
+    ((hpa as f64 / 33.8639 * 100.0).round() / 100.0) as f32
+}
+
+// the units /api/convert knows, as (name, quantity, how many of the quantity's base unit one of them is).
+// temperature isn't a plain ratio, so its entries are handled on their own
+const CONVERSION_UNITS: &[(&str, &str, f64)] = &[
+    ("hpa", "pressure", 1.0),
+    ("inhg", "pressure", 33.8639),
+    ("kt", "speed", 1.0),
+    ("mph", "speed", 0.868976),
+    ("kmh", "speed", 0.539957),
+    ("m", "distance", 1.0),
+    ("sm", "distance", 1609.344),
+    ("c", "temperature", 1.0),
+    ("f", "temperature", 1.0),
+];
+
+// converts a value between two units from CONVERSION_UNITS, like 29.92 "inhg" to "hpa".
+// None when either unit is unknown or they measure different things
+pub fn convert_unit(value: f64, from: &str, to: &str) -> Option<f64> {
+    let (_, from_quantity, from_factor) = CONVERSION_UNITS.iter().find(|(name, _, _)| *name == from)?;
+    let (_, to_quantity, to_factor) = CONVERSION_UNITS.iter().find(|(name, _, _)| *name == to)?;
+    if from_quantity != to_quantity {
+        return None;
+    }
+    match (from, to) {
+        ("c", "f") => Some(value * 9.0 / 5.0 + 32.0),
+        ("f", "c") => Some((value - 32.0) * 5.0 / 9.0),
+        _ => Some(value * from_factor / to_factor),
+    }
+}
+
+// the unit names convert_unit accepts, for error messages
+pub fn conversion_unit_names() -> Vec<&'static str> {
+    CONVERSION_UNITS.iter().map(|(name, _, _)| *name).collect()
+}
+
+// plain-language remarks, matched token by token against the start of the remaining remarks
+const PLAIN_LANGUAGE_REMARKS: &[(&[&str], &str)] = &[
+    (&["ACFT", "MSHP"], "Aircraft mishap"),
+    (&["FUNNEL", "CLOUD"], "Funnel cloud"),
+    (&["TORNADO"], "Tornado"),
+    (&["WATERSPOUT"], "Waterspout"),
+    (&["PRESRR"], "Pressure rising rapidly"),
+    (&["PRESFR"], "Pressure falling rapidly"),
+    (&["NOSPECI"], "No special reports issued"),
+    (&["FROIN"], "Frost on the indicator"),
+    (&["FIBI"], "Filed but impracticable to transmit"),
+    (&["WND", "DATA", "ESTMD"], "Wind data estimated"),
+    (&["ALSTG", "ESTMD"], "Altimeter setting estimated"),
+    // observer notes
+    (&["CIG", "RAG"], "Ragged ceiling"),
+    (&["CONTRAILS"], "Condensation trails"),
+    (&["FU", "ALOFT"], "Smoke aloft"),
+    (&["ACSL"], "Standing lenticular altocumulus"),
+    (&["CCSL"], "Standing lenticular cirrocumulus"),
+    (&["SCSL"], "Standing lenticular stratocumulus"),
+];
+
+// icing intensity and type for the digit after the "6" in an icing group
+const ICING_TYPES: [&str; 10] = [
+    "No icing",
+    "Light icing",
+    "Light icing in cloud",
+    "Light icing in precipitation",
+    "Moderate icing",
+    "Moderate icing in cloud",
+    "Moderate icing in precipitation",
+    "Severe icing",
+    "Severe icing in cloud",
+    "Severe icing in precipitation",
+];
+
+// turbulence intensity and frequency for the digit after the "5" in a turbulence group
+const TURBULENCE_TYPES: [&str; 10] = [
+    "No turbulence",
+    "Light turbulence",
+    "Occasional moderate turbulence in clear air",
+    "Frequent moderate turbulence in clear air",
+    "Occasional moderate turbulence in cloud",
+    "Frequent moderate turbulence in cloud",
+    "Occasional severe turbulence in clear air",
+    "Frequent severe turbulence in clear air",
+    "Occasional severe turbulence in cloud",
+    "Frequent severe turbulence in cloud",
+];
+
+// decodes a runway visual range group. meters are the default and "FT" marks feet, a "P" or "M" before a
+// value means more or less than it, "V" joins a varying range and a trailing "U", "D" or "N" is the tendency,
+// like "R25/0650U", "R25/0600V1200N" or "R28L/1200V1800FT/U"
+pub fn runway_visual_range(code: &str) -> Option<RunwayVisualRange> {
+    let (runway, rest) = code.strip_prefix('R')?.split_once('/')?;
+    let digits = runway.trim_end_matches(['L', 'R', 'C']);
+    if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_digit()) || runway.len() > 3 {
+        return None;
+    }
+
+    let rest = rest.trim_end_matches('/');
+    let (rest, tendency) = match rest.chars().last() {
+        Some('U') => (&rest[..rest.len() - 1], Some("increasing")),
+        Some('D') => (&rest[..rest.len() - 1], Some("decreasing")),
+        Some('N') => (&rest[..rest.len() - 1], Some("no change")),
+        _ => (rest, None),
+    };
+    let rest = rest.trim_end_matches('/');
+    let (range, unit) = match rest.strip_suffix("FT") {
+        Some(range) => (range, "feet"),
+        None => (rest, "meters"),
+    };
+
+    // a value with its "P"/"M" prefix, as the number and how to describe it
+    let value = |text: &str| -> Option<(u32, String)> {
+        let (prefix, number) = match text.chars().next()? {
+            'P' => ("more than ", &text[1..]),
+            'M' => ("less than ", &text[1..]),
+            _ => ("", text),
+        };
+        if number.len() != 4 || !number.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let number = number.parse::<u32>().ok()?;
+        Some((number, format!("{}{} {}", prefix, number, unit)))
+    };
+    let (low, high) = match range.split_once('V') {
+        Some((low, high)) => (value(low)?, Some(value(high)?)),
+        None => (value(range)?, None),
+    };
+
+    let mut description = match &high {
+        Some((_, high_text)) => format!("Runway {} visual range {} to {}", runway, low.1, high_text),
+        None => format!("Runway {} visual range {}", runway, low.1),
+    };
+    if let Some(tendency) = tendency {
+        description.push_str(&format!(", {}", tendency));
+    }
+    Some(RunwayVisualRange {
+        runway: runway.to_string(),
+        value: low.0,
+        max_value: high.map(|(number, _)| number),
+        unit: unit.to_string(),
+        tendency: tendency.map(str::to_string),
+        description,
+    })
+}
+
+// reads the precise temperature remark, like "T01830122" (18.3°C, dewpoint 12.2°C) or "T1012" (-1.2°C, no dewpoint).
+// each value is a sign digit, 1 for below zero, and three digits of tenths. the older "T183/122" layout is read too
+pub fn precise_temperature(code: &str) -> Option<(f32, Option<f32>)> {
+    let body = code.strip_prefix('T')?;
+    if let Some((temp, dew)) = body.split_once('/') {
+        let temp = temp.parse::<i32>().ok()? as f32 / 10.0;
+        let dew = dew.parse::<i32>().ok()? as f32 / 10.0;
+        return Some((temp, Some(dew)));
+    }
+    if !body.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let value = |group: &str| -> Option<f32> {
+        let tenths = group[1..].parse::<u32>().ok()? as f32 / 10.0;
+        match &group[0..1] {
+            "0" => Some(tenths),
+            "1" => Some(-tenths),
+            _ => None,
+        }
+    };
+    match body.len() {
+        4 => Some((value(body)?, None)),
+        8 => Some((value(&body[0..4])?, Some(value(&body[4..8])?))),
+        _ => None,
+    }
+}
+
+// low cloud types for the first digit of an "8/" group, from WMO code table 0513
+const LOW_CLOUD_TYPES: [&str; 10] = [
+    "No low clouds",
+    "Fair weather cumulus",
+    "Towering cumulus",
+    "Cumulonimbus without an anvil",
+    "Stratocumulus from spreading cumulus",
+    "Stratocumulus",
+    "Stratus",
+    "Ragged stratus or cumulus of bad weather",
+    "Cumulus and stratocumulus at different levels",
+    "Cumulonimbus with an anvil",
+];
+
+// middle cloud types for the second digit, from WMO code table 0515
+const MIDDLE_CLOUD_TYPES: [&str; 10] = [
+    "No middle clouds",
+    "Thin altostratus",
+    "Thick altostratus or nimbostratus",
+    "Thin altocumulus at one level",
+    "Patches of thin altocumulus",
+    "Thin altocumulus in bands",
+    "Altocumulus from spreading cumulus",
+    "Thick or layered altocumulus",
+    "Altocumulus with turrets or tufts",
+    "Altocumulus of a chaotic sky",
+];
+
+// high cloud types for the third digit, from WMO code table 0509
+const HIGH_CLOUD_TYPES: [&str; 10] = [
+    "No high clouds",
+    "Cirrus filaments",
+    "Dense cirrus in patches",
+    "Cirrus from a cumulonimbus anvil",
+    "Cirrus spreading over the sky",
+    "Cirrus and cirrostratus, low in the sky",
+    "Cirrus and cirrostratus, high in the sky",
+    "Cirrostratus covering the sky",
+    "Cirrostratus not covering the sky",
+    "Cirrocumulus",
+];
+
+// decodes a cloud type group like "8/578" into its low, middle and high layers, "/" being a layer that couldn't be seen
+pub fn cloud_types(code: &str) -> Option<CloudTypes> {
+    let digits = code.strip_prefix("8/")?;
+    if digits.len() != 3 || !digits.chars().all(|c| c.is_ascii_digit() || c == '/') || digits == "///" {
+        return None;
+    }
+    let layer = |index: usize, types: &[&str; 10]| {
+        digits[index..index + 1]
+            .parse::<usize>()
+            .ok()
+            .map(|digit| types[digit].to_string())
+    };
+    Some(CloudTypes {
+        low: layer(0, &LOW_CLOUD_TYPES),
+        middle: layer(1, &MIDDLE_CLOUD_TYPES),
+        high: layer(2, &HIGH_CLOUD_TYPES),
+    })
+}
+
+// decodes an icing group like "620304" (light icing in cloud from 3000 to 7000 feet):
+// the type, the base in hundreds of feet and the thickness in thousands of feet
+pub fn icing_layer(code: &str) -> Option<HazardLayer> {
+    hazard_layer(code, '6', &ICING_TYPES)
+}
+
+// decodes a turbulence group like "540302", laid out the same way as an icing group
+pub fn turbulence_layer(code: &str) -> Option<HazardLayer> {
+    hazard_layer(code, '5', &TURBULENCE_TYPES)
+}
+
+// reads a "6IhhhT" or "5BhhhT" group led by the given digit, with the type digit looked up in types
+pub fn hazard_layer(code: &str, lead: char, types: &[&str; 10]) -> Option<HazardLayer> {
+    if code.len() != 6 || !code.starts_with(lead) || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let digit = |index: usize| code[index..index + 1].parse::<u32>().ok();
+    let kind = types[digit(1)? as usize];
+    let base_feet = code[2..5].parse::<u32>().ok()? * 100;
+    let top_feet = base_feet + digit(5)? * 1000;
+    Some(HazardLayer {
+        description: format!("{} from {} to {} feet", kind, base_feet, top_feet),
+        base_feet,
+        top_feet,
+    })
+}
+
+// looks up a plain-language remark at the start of parts, returns the text and how many tokens it used
+pub fn plain_language_remark(parts: &[&str]) -> Option<(&'static str, usize)> {
+    PLAIN_LANGUAGE_REMARKS
+        .iter()
+        .find(|(tokens, _)| parts.starts_with(tokens))
+        .map(|(tokens, text)| (*text, tokens.len()))
+}
+
+// names the remark group behind a "NO" (not available) sentinel, like "SLPNO" or "PNO"
+pub fn not_available_label(code: &str) -> Option<&'static str> {
+    let group = code.strip_suffix("NO")?;
+    match group {
+        "SLP" => Some("Sea-level pressure"),
+        "RVR" => Some("Runway visual range"),
+        "P" => Some("Hourly precipitation"),
+        "PWI" => Some("Present weather identifier"),
+        "FZRA" => Some("Freezing rain sensor"),
+        "TS" => Some("Lightning detection"),
+        "VIS" => Some("Secondary visibility sensor"),
+        "CHI" => Some("Secondary ceiling sensor"),
+        _ => None,
+    }
+}
+
+// describes where a secondary sensor sits, like "RWY24" (runway 24) or "NE" (to the northeast)
+pub fn sensor_location(code: &str) -> Option<String> {
+    if let Some(runway) = code.strip_prefix("RWY") {
+        let digits = runway.trim_end_matches(['L', 'R', 'C']);
+        if digits.len() == 2 && digits.chars().all(|c| c.is_ascii_digit()) && runway.len() <= 3 {
+            return Some(format!("runway {}", runway));
+        }
+        return None;
+    }
+    expand_direction(code).map(|direction| format!("to the {}", direction))
+}
+
+// bounds for how long a decoded report may be cached by clients
+pub const MIN_CACHE_SECONDS: u64 = 60;
+pub const MAX_CACHE_SECONDS: u64 = 1800;
+
+// works out how long until the next routine report, which comes about an hour after the last observation.
+// only the time of day is compared, so a report more than a day old looks fresh again, but feeds don't serve those.
+// the result is clamped so specials still get picked up and a late report isn't hammered
+pub fn cache_max_age(obs_hour: u32, obs_minute: u32, now_unix_secs: u64) -> u64 {
+    let now_minute_of_day = (now_unix_secs / 60) % 1440;
+    let obs_minute_of_day = (obs_hour as u64 * 60 + obs_minute as u64) % 1440;
+    let age_minutes = (now_minute_of_day + 1440 - obs_minute_of_day) % 1440;
+
+    if age_minutes >= 60 {
+        return MIN_CACHE_SECONDS;
+    }
+    let remaining = (60 - age_minutes) * 60 - now_unix_secs % 60;
+    remaining.clamp(MIN_CACHE_SECONDS, MAX_CACHE_SECONDS)
+}
+
+// decodes weather begin/end times from remarks, like "RAB05E20" (rain began :05, ended :20) or "SNB1215" (snow began 12:15Z).
+// the phenomenon can carry a descriptor ("FZRAB05") and times are minutes past the hour or hhmm
+pub fn decode_begin_end_remark(code: &str) -> Option<String> {
+    let first_digit = code.find(|c: char| c.is_ascii_digit())?;
+    if first_digit < 3 || !code.is_char_boundary(first_digit - 1) {
+        return None;
+    }
+    let phenomenon = &code[..first_digit - 1];
+    if !(phenomenon.len() == 2 || phenomenon.len() == 4) || !phenomenon.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+
+    let name = decode_weather(phenomenon);
+    if name.is_empty() {
+        return None;
+    }
+
+    // walk the rest as (B|E)(time) pairs
+    let mut events = Vec::new();
+    let mut rest = &code[first_digit - 1..];
+    while !rest.is_empty() {
+        let verb = match rest.as_bytes()[0] {
+            b'B' => "began",
+            b'E' => "ended",
+            _ => return None,
+        };
+        let digits_len = rest[1..].find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len() - 1);
+        let time = &rest[1..1 + digits_len];
+        let when = match time.len() {
+            2 => format!("{} minutes past the hour", time.parse::<u32>().ok()?),
+            4 => format!("{}:{}Z", &time[0..2], &time[2..4]),
+            _ => return None,
+        };
+        events.push(format!("{} at {}", verb, when));
+        rest = &rest[1 + digits_len..];
+    }
+
+    let mut chars = name.chars();
+    let capitalized = match chars.next() {
+        Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
+        None => return None,
+    };
+    Some(format!("{} {}", capitalized, events.join(", ")))
+}
+
+// the block characters a sparkline is drawn with, lowest to highest
+const SPARKLINE_BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// draws the values as block characters scaled between the lowest and highest, level values sit mid-height
+pub fn sparkline(values: &[f32]) -> String {
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    values
+        .iter()
+        .map(|value| {
+            if max <= min {
+                SPARKLINE_BLOCKS[3]
+            } else {
+                SPARKLINE_BLOCKS[((value - min) / (max - min) * 7.0).round() as usize]
+            }
+        })
+        .collect()
+}
+
+// formats a number with a fixed number of decimal places
+pub fn format_decimal(value: f64, places: usize) -> String {
+    format!("{:.*}", places, value)
+}
+
 // converts temperature from celsius to fahrenheit
 pub fn celsius_to_fahrenheit(celsius: i32) -> i32 {
     (celsius * 9 / 5) + 32
 }
 
-// formats a stat value for display, returns a css class and the value to show (or default if empty)
+// formats a stat value for display, returns a css class and the html-escaped value to show (or default if empty)
 pub fn format_stat_value(value: &str, default: &str) -> (String, String) {
     if value.is_empty() {
         (" empty".to_string(), default.to_string())
     } else {
-        ("".to_string(), value.to_string())
+        ("".to_string(), escape_html(value))
+    }
+}
+
+// orders flight categories from worst to best, LIFR 0 up to VFR 3, None for anything else
+pub fn category_rank(category: &str) -> Option<u8> {
+    match category {
+        "LIFR" => Some(0),
+        "IFR" => Some(1),
+        "MVFR" => Some(2),
+        "VFR" => Some(3),
+        _ => None,
+    }
+}
+
+// weather that limits operations whatever the visibility, checked pair by pair after the intensity and "VC"
+const SIGNIFICANT_WEATHER: &[&str] = &["TS", "FZ", "GR", "GS", "PL", "FC", "SQ", "VA", "SS", "DS"];
+
+// wind speeds, sustained or gusting, that count as MVFR and IFR for the limiting factor
+const STRONG_WIND_KNOTS: u32 = 25;
+const SEVERE_WIND_KNOTS: u32 = 35;
+
+// checks if a weather code like "+RA", "TSRA" or "-FZDZ" is heavy or one of the significant phenomena
+pub fn is_significant_weather(code: &str) -> bool {
+    if code.starts_with('+') {
+        return true;
     }
+    let code = code.trim_start_matches('-').trim_start_matches("VC");
+    code.as_bytes()
+        .chunks(2)
+        .any(|pair| SIGNIFICANT_WEATHER.iter().any(|significant| significant.as_bytes() == pair))
 }
 
+// the field doing the most to limit operations: "visibility", "ceiling", "wind" or "weather", None when all are at VFR.
+// each is ranked like category_rank, wind by its own thresholds and significant weather as IFR, ties go in that order
+pub fn limiting_factor(
+    ceiling_feet: Option<u32>,
+    visibility_miles: Option<f32>,
+    wind_knots: Option<u32>,
+    significant_weather: bool,
+) -> Option<&'static str> {
+    let wind_rank = |knots: u32| {
+        if knots >= SEVERE_WIND_KNOTS {
+            1
+        } else if knots >= STRONG_WIND_KNOTS {
+            2
+        } else {
+            3
+        }
+    };
+    let ranks = [
+        ("visibility", visibility_miles.and_then(|miles| category_rank(flight_category(None, miles)))),
+        ("ceiling", ceiling_feet.and_then(|feet| category_rank(flight_category(Some(feet), f32::MAX)))),
+        ("wind", wind_knots.map(wind_rank)),
+        ("weather", significant_weather.then_some(1)),
+    ];
+    ranks
+        .iter()
+        .filter_map(|(field, rank)| Some((*field, (*rank)?)))
+        .filter(|(_, rank)| *rank < 3)
+        .min_by_key(|(_, rank)| *rank)
+        .map(|(field, _)| field)
+}
+
+// wraps a value in single quotes for a shell, closing and reopening the quotes around any quote inside it
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// checks that a code is a plain station identifier, 4 letters/digits for ICAO or 3 for IATA
+pub fn is_valid_station_code(code: &str) -> bool {
+    (code.len() == 3 || code.len() == 4) && code.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// compares two byte strings without bailing out at the first difference, so timing doesn't leak the secret
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// escapes text so it can be safely placed inside html markup
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// expands a compass direction used in remarks like "SE" to "southeast", also handles ranges like "NE-E"
+pub fn expand_direction(code: &str) -> Option<String> {
+    let single = |d: &str| -> Option<&'static str> {
+        match d {
+            "N" => Some("north"),
+            "NE" => Some("northeast"),
+            "E" => Some("east"),
+            "SE" => Some("southeast"),
+            "S" => Some("south"),
+            "SW" => Some("southwest"),
+            "W" => Some("west"),
+            "NW" => Some("northwest"),
+            _ => None,
+        }
+    };
+
+    if let Some((from, to)) = code.split_once('-') {
+        return Some(format!("{} through {}", single(from)?, single(to)?));
+    }
+    single(code).map(|d| d.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intensity_only_applies_to_precipitation() {
+        assert_eq!(decode_weather("-BR"), "mist");
+        assert_eq!(decode_weather("-RA"), "Light rain");
+        assert_eq!(decode_weather("+RA"), "Heavy rain");
+    }
+
+    #[test]
+    fn altimeter_conversions_round() {
+        assert_eq!(inhg_hundredths_to_hpa(2992), 1013);
+        assert_eq!(inhg_hundredths_to_hpa(2953), 1000);
+        assert_eq!(hpa_to_inhg(1013), 29.91);
+        assert_eq!(hpa_to_inhg(1000), 29.53);
+    }
+
+    #[test]
+    fn not_available_labels() {
+        assert_eq!(not_available_label("SLPNO"), Some("Sea-level pressure"));
+        assert_eq!(not_available_label("PNO"), Some("Hourly precipitation"));
+        assert_eq!(not_available_label("NO"), None);
+        assert_eq!(not_available_label("XYZNO"), None);
+    }
+
+    #[test]
+    fn cache_max_age_runs_to_the_next_observation() {
+        // some day at 16:31:30Z, for a report observed at 15:51Z
+        let now = 20_000 * 86_400 + 16 * 3600 + 31 * 60 + 30;
+        assert_eq!(cache_max_age(15, 51, now), 20 * 60 - 30);
+        // fresh reports are capped, overdue ones get the minimum
+        assert_eq!(cache_max_age(16, 30, now), MAX_CACHE_SECONDS);
+        assert_eq!(cache_max_age(14, 51, now), MIN_CACHE_SECONDS);
+        // an observation from just before midnight
+        let after_midnight = 20_000 * 86_400 + 40 * 60;
+        assert_eq!(cache_max_age(23, 51, after_midnight), 11 * 60);
+    }
+
+    #[test]
+    fn composite_fog_codes() {
+        assert_eq!(decode_weather("BCFG"), "Patches of fog");
+        assert_eq!(decode_weather("MIFG"), "Shallow fog");
+        assert_eq!(decode_weather("PRFG"), "Partial fog");
+    }
+
+    #[test]
+    fn begin_end_remarks() {
+        assert_eq!(
+            decode_begin_end_remark("RAB05E20").as_deref(),
+            Some("Rain began at 5 minutes past the hour, ended at 20 minutes past the hour")
+        );
+        assert_eq!(decode_begin_end_remark("SNB1215").as_deref(), Some("Snow began at 12:15Z"));
+        assert_eq!(decode_begin_end_remark("RMK"), None);
+    }
+
+    #[test]
+    fn format_decimal_places() {
+        assert_eq!(format_decimal(53.96, 1), "54.0");
+        assert_eq!(format_decimal(53.96, 0), "54");
+        assert_eq!(format_decimal(0.09, 3), "0.090");
+    }
+
+    #[test]
+    fn icing_and_turbulence_layers() {
+        let icing = icing_layer("620304").unwrap();
+        assert_eq!(icing.description, "Light icing in cloud from 3000 to 7000 feet");
+        assert_eq!((icing.base_feet, icing.top_feet), (3000, 7000));
+
+        let turbulence = turbulence_layer("540302").unwrap();
+        assert_eq!(turbulence.description, "Occasional moderate turbulence in cloud from 3000 to 5000 feet");
+        assert_eq!((turbulence.base_feet, turbulence.top_feet), (3000, 5000));
+
+        // the wrong lead digit, length or a letter
+        assert!(icing_layer("540302").is_none());
+        assert!(turbulence_layer("54030").is_none());
+        assert!(icing_layer("62030X").is_none());
+    }
+
+    #[test]
+    fn each_conversion_pair() {
+        let pairs = [
+            (29.92, "inhg", "hpa", 1013.21),
+            (1013.25, "hpa", "inhg", 29.92),
+            (15.0, "c", "f", 59.0),
+            (-40.0, "f", "c", -40.0),
+            (10.0, "kt", "mph", 11.51),
+            (10.0, "mph", "kt", 8.69),
+            (10.0, "kt", "kmh", 18.52),
+            (18.52, "kmh", "kt", 10.0),
+            (60.0, "mph", "kmh", 96.56),
+            (100.0, "kmh", "mph", 62.14),
+            (1609.344, "m", "sm", 1.0),
+            (10.0, "sm", "m", 16093.44),
+        ];
+        for (value, from, to, expected) in pairs {
+            let result = convert_unit(value, from, to).unwrap();
+            assert!((result - expected).abs() < 0.01, "{value} {from} to {to} gave {result}, expected {expected}");
+        }
+        assert_eq!(convert_unit(1.0, "kt", "hpa"), None);
+        assert_eq!(convert_unit(1.0, "knots", "mph"), None);
+    }
+}